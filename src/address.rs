@@ -0,0 +1,699 @@
+//! Postkeeper email address helpers
+
+use crate::consts::default;
+
+/// Options applied to addresses before they are looked up in maps
+///
+/// EXAMPLE with `recipient_delimiter = +`:
+///  recipient `alice+shop@example.com` is looked up as itself first, then as
+///  `alice@example.com`. With `strip_sender_detail = yes` sender entry
+///  `bob@example.org` also matches sender `bob+news@example.org`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AddressOptions {
+    recipient_delimiter: Option<String>,
+    strip_sender_detail: bool,
+    sender_match: SenderMatch,
+    decoders: Decoders,
+}
+
+/// Sender address encodings that are decoded before lookup, the decoded
+/// address is matched in addition to the sender as sent
+///
+/// EXAMPLE:
+///  `SRS0=hash=tt=orig.example=bob@fwd.example`  is `bob@orig.example` (srs)
+///  `prvs=1234abcd=bob@example.com`  is `bob@example.com` (batv)
+///  `list+bob=example.com@lists.example`  is `list@lists.example` (verp)
+///
+/// the default enables the same decoders as the configuration defaults
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decoders {
+    /// SRS0 and SRS1 addresses of forwarders (Sender Rewriting Scheme)
+    pub srs: bool,
+    /// BATV `prvs=` signed bounce addresses
+    pub batv: bool,
+    /// VERP local parts that carry the recipient `user=domain`
+    pub verp: bool,
+}
+
+/// Which sender address of a message a map entry is matched against
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SenderMatch {
+    /// envelope sender (`MAIL FROM`)
+    #[default]
+    Envelope,
+    /// address of the `From:` header
+    Header,
+    /// envelope sender or `From:` header address
+    Both,
+}
+
+impl AddressOptions {
+    /// `recipient_delimiter` is a set of characters, any of them starts the
+    /// detail part of an address (like postfix `recipient_delimiter`)
+    /// `sender_match` applies to map entries without a `match` option
+    pub fn new(
+        recipient_delimiter: Option<String>,
+        strip_sender_detail: bool,
+        sender_match: SenderMatch,
+        decoders: Decoders,
+    ) -> Self {
+        Self {
+            recipient_delimiter,
+            strip_sender_detail,
+            sender_match,
+            decoders,
+        }
+    }
+
+    /// sender address entries are matched against by default
+    pub fn sender_match(&self) -> SenderMatch {
+        self.sender_match
+    }
+
+    /// recipient followed by its address without the detail part if any
+    pub fn recipient_variants(&self, recipient: &str) -> Vec<String> {
+        self.variants(recipient, true)
+    }
+
+    /// sender followed by its address without the detail part, only if
+    /// `strip_sender_detail` is enabled, then the decoded sender (and its
+    /// address without the detail part) if it is encoded, see [`Decoders`]
+    pub fn sender_variants(&self, sender: &str) -> Vec<String> {
+        let mut variants = self.variants(sender, self.strip_sender_detail);
+        if let Some(decoded) = self.decoders.decode(sender) {
+            log::debug!("Sender `{}` decoded as `{}`", sender, decoded);
+            variants.extend(self.variants(&decoded, self.strip_sender_detail));
+        }
+        variants
+    }
+
+    /// decoded sender if it is encoded, see [`Decoders`]
+    pub fn decode(&self, sender: &str) -> Option<String> {
+        self.decoders.decode(sender)
+    }
+
+    /// same options with every decoder disabled, senders are only matched
+    /// as sent
+    pub fn without_decoders(&self) -> Self {
+        Self {
+            decoders: Decoders::NONE,
+            ..self.clone()
+        }
+    }
+
+    fn variants(&self, address: &str, strip: bool) -> Vec<String> {
+        let mut variants = vec![address.to_owned()];
+        let delimiter = self.recipient_delimiter.as_deref();
+        if let Some(stripped) = delimiter
+            .filter(|_| strip)
+            .and_then(|delimiter| strip_detail(address, delimiter))
+        {
+            variants.push(stripped);
+        }
+        variants
+    }
+}
+
+impl SenderMatch {
+    /// parses `envelope`, `header` or `both`, None for any other value
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "envelope" => Some(SenderMatch::Envelope),
+            "header" => Some(SenderMatch::Header),
+            "both" => Some(SenderMatch::Both),
+            _ => None,
+        }
+    }
+
+    /// true if an entry with this setting is matched against `address`,
+    /// the kind of sender address (`Envelope` or `Header`)
+    pub fn applies_to(self, address: SenderMatch) -> bool {
+        self == SenderMatch::Both || self == address
+    }
+}
+
+impl Default for Decoders {
+    fn default() -> Self {
+        Self {
+            srs: default::DECODE_SRS,
+            batv: default::DECODE_BATV,
+            verp: default::COLLAPSE_VERP,
+        }
+    }
+}
+
+impl Decoders {
+    /// no address is decoded
+    pub const NONE: Self = Self {
+        srs: false,
+        batv: false,
+        verp: false,
+    };
+
+    /// original address of an encoded address, enabled decoders are applied
+    /// in order SRS, BATV, VERP. None if nothing is decoded
+    pub fn decode(&self, address: &str) -> Option<String> {
+        let mut decoded = address.to_owned();
+        if self.srs {
+            decoded = decode_srs(&decoded).unwrap_or(decoded);
+        }
+        if self.batv {
+            decoded = decode_batv(&decoded).unwrap_or(decoded);
+        }
+        if self.verp {
+            decoded = collapse_verp(&decoded).unwrap_or(decoded);
+        }
+        Some(decoded).filter(|decoded| decoded != address)
+    }
+}
+
+// `SRS0=hash=tt=orig.example=bob@fwd.example` is `bob@orig.example`
+// `SRS1=hash=fwd1.example==hash=tt=orig.example=bob@fwd2.example` too, the
+// separator after `SRS0`/`SRS1` can be `=`, `+` or `-`
+fn decode_srs(address: &str) -> Option<String> {
+    let (local, _) = address.rsplit_once('@')?;
+    let (prefix, mut rest) = local.split_at_checked(4)?;
+    if prefix.eq_ignore_ascii_case("srs1") {
+        // skip hash and first forwarder, SRS0 part follows
+        let mut fields = rest.get(1..)?.splitn(3, '=');
+        rest = fields.nth(2)?;
+    } else if !prefix.eq_ignore_ascii_case("srs0") {
+        return None;
+    }
+
+    let mut fields = rest.strip_prefix(['=', '+', '-'])?.splitn(4, '=');
+    let (domain, local) = (fields.nth(2)?, fields.next()?);
+    if domain.is_empty() || local.is_empty() {
+        return None;
+    }
+    Some(format!("{}@{}", local, domain))
+}
+
+// `prvs=1234abcd=bob@example.com` is `bob@example.com`
+fn decode_batv(address: &str) -> Option<String> {
+    let (local, domain) = address.rsplit_once('@')?;
+    let (prefix, rest) = local.split_at_checked(5)?;
+    if !prefix.eq_ignore_ascii_case("prvs=") {
+        return None;
+    }
+    let (_, local) = rest.split_once('=').filter(|(_, l)| !l.is_empty())?;
+    Some(format!("{}@{}", local, domain))
+}
+
+// `list+bob=example.com@lists.example` is `list@lists.example`, local part
+// is cut at the last `+` or `-` before the encoded `user=domain`
+fn collapse_verp(address: &str) -> Option<String> {
+    let (local, domain) = address.rsplit_once('@')?;
+    let encoded = local.find('=')?;
+    let index = local[..encoded].rfind(['+', '-']).filter(|i| *i > 0)?;
+    Some(format!("{}@{}", &local[..index], domain))
+}
+
+/// canonical form of an address used for map keys, entries and lookups
+/// local part is unicode case folded, domain part is converted
+/// to lowercase IDNA ASCII (punycode) so `bob@Bücher.example` and
+/// `bob@xn--bcher-kva.example` compare equal.
+/// values without `@` (i.e. `*`) are case folded
+pub fn canonicalize(address: &str) -> String {
+    match address.rsplit_once('@') {
+        Some((local, domain)) => {
+            format!("{}@{}", case_fold(local), canonical_domain(domain))
+        }
+        None => case_fold(address),
+    }
+}
+
+// unicode full case folding, strings that only differ in case compare equal
+// i.e. `STRASSE`, `Straße` and `strasse` all fold to `strasse`
+// upper casing expands `ß` and ligatures like `ﬁ`, lower casing the result
+// folds the rest, final sigma `ς` folds to `σ` like any other sigma
+fn case_fold(value: &str) -> String {
+    value.to_uppercase().to_lowercase().replace('ς', "σ")
+}
+
+/// lowercase IDNA ASCII form of a domain, domains that are not valid IDNA are
+/// only lowercased
+pub fn canonical_domain(domain: &str) -> String {
+    match idna::domain_to_ascii(domain) {
+        Ok(ascii) => ascii,
+        Err(_) => {
+            log::debug!("Domain `{}` is not a valid IDNA domain", domain);
+            domain.to_lowercase()
+        }
+    }
+}
+
+/// returns the domain part of an address (after the last `@`)
+pub fn domain_of(address: &str) -> Option<&str> {
+    address
+        .rsplit_once('@')
+        .map(|(_, domain)| domain)
+        .filter(|domain| !domain.is_empty())
+}
+
+/// true if `domain` is a subdomain of `parent`, `parent` itself does not match
+pub fn is_subdomain_of(domain: &str, parent: &str) -> bool {
+    domain
+        .strip_suffix(parent)
+        .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.'))
+}
+
+/// address without its detail part, local part is cut at the first character
+/// of `delimiters`, i.e. `alice+shop@example.com` becomes `alice@example.com`
+/// returns None if the address has no detail part, or the local part would be
+/// empty (`+shop@example.com`)
+pub fn strip_detail(address: &str, delimiters: &str) -> Option<String> {
+    let (local, domain) = match address.rsplit_once('@') {
+        Some((local, domain)) => (local, Some(domain)),
+        None => (address, None),
+    };
+
+    let index = local.find(|c| delimiters.contains(c))?;
+    if index == 0 {
+        return None;
+    }
+
+    let local = &local[..index];
+    Some(match domain {
+        Some(domain) => format!("{}@{}", local, domain),
+        None => local.to_owned(),
+    })
+}
+
+/// address of a `MAIL FROM` or `RCPT TO` command from the arguments the MTA
+/// passes to the milter, `<bob@example.org>` followed by ESMTP parameters
+/// i.e. `SIZE=1024`, angle brackets and a source route
+/// (`<@relay.example:bob@example.org>`) are removed, `<>` is an empty address
+/// returns None if there are no arguments
+pub fn smtp_address(smtp_args: &[&str]) -> Option<String> {
+    let arg = smtp_args.first()?.trim();
+    let address = match arg.strip_prefix('<') {
+        Some(rest) => rest.split_once('>').map_or(rest, |(address, _)| address),
+        None => arg.split_whitespace().next().unwrap_or_default(),
+    };
+    let address = match address.split_once(':') {
+        Some((route, mailbox)) if route.starts_with('@') => mailbox,
+        _ => address,
+    };
+    Some(address.trim().to_owned())
+}
+
+/// list identifier of a `List-Id` header value (RFC 2919)
+/// i.e. `Developers <dev.lists.example.org>`, the phrase is skipped
+/// returns None if the value has no identifier, an identifier always has a
+/// label and a namespace separated by `.`
+pub fn list_id(value: &str) -> Option<String> {
+    let id = match value.rsplit_once('<') {
+        Some((_, rest)) => rest.split_once('>')?.0,
+        None => value,
+    };
+    Some(id.trim())
+        .filter(|id| id.contains('.') && !id.contains(char::is_whitespace))
+        .map(str::to_owned)
+}
+
+/// posting address of a `List-Post` header value (RFC 2369), first `mailto:`
+/// URL of the value without its query i.e.
+/// `<mailto:dev@lists.example.org?subject=help>`
+/// returns None for `NO` (posting is not allowed) or without `mailto:` URL
+pub fn list_post_address(value: &str) -> Option<String> {
+    value.split('<').skip(1).find_map(|url| {
+        let url = url.split_once('>')?.0.trim();
+        let address = url
+            .get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("mailto:"))
+            .and_then(|_| url[7..].split('?').next())?;
+        Some(address)
+            .filter(|address| address.contains('@'))
+            .map(str::to_owned)
+    })
+}
+
+/// address of the first mailbox of a `From:` header value (RFC 5322)
+/// i.e. `"Doe, Jane" <jane@example.com>` or `jane@example.com (Jane Doe)`
+/// display names, comments and group names are skipped
+/// returns None if the value has no address
+pub fn header_address(value: &str) -> Option<String> {
+    header_mailbox(value).map(|(_, address)| address)
+}
+
+/// display name of the first mailbox of a `From:` header value, see
+/// [`header_address`], unquoted with whitespace folded
+/// i.e. `Doe, Jane` of `"Doe, Jane" <jane@example.com>`
+/// returns None if the mailbox has no display name
+pub fn header_display_name(value: &str) -> Option<String> {
+    header_mailbox(value)
+        .map(|(name, _)| name)
+        .filter(|name| !name.is_empty())
+}
+
+/// true if a display name claims to be given canonical address: the name
+/// has the address in it or the same words as its local part, at least two
+/// i.e. `Jane CFO` and `cfo, jane` for `jane.cfo@example.com`, a single
+/// word like `Support` of `support@example.com` is too common to tell
+pub fn display_name_matches(name: &str, address: &str) -> bool {
+    let has_address = name
+        .split(|c: char| c.is_whitespace() || "<>()[]\"',;".contains(c))
+        .any(|token| token.contains('@') && canonicalize(token) == address);
+    let words = |value: &str| {
+        let mut words: Vec<String> = value
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(case_fold)
+            .collect();
+        words.sort();
+        words
+    };
+    let local = address.rsplit_once('@').map(|(local, _)| local);
+    has_address
+        || local.is_some_and(|local| {
+            let name = words(name);
+            name.len() > 1 && name == words(local)
+        })
+}
+
+// display name (empty if none) and address of the first mailbox of a header
+// value, None if it has no address
+fn header_mailbox(value: &str) -> Option<(String, String)> {
+    let mut bare = String::new();
+    let mut angle: Option<String> = None;
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut comment = 0;
+
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && (quoted || comment > 0) {
+            escaped = true;
+            continue;
+        } else if comment > 0 {
+            match c {
+                '(' => comment += 1,
+                ')' => comment -= 1,
+                _ => {}
+            }
+            continue;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if !quoted {
+            match (c, angle.is_some()) {
+                ('(', _) => {
+                    comment += 1;
+                    continue;
+                }
+                ('<', false) => {
+                    angle = Some(String::new());
+                    continue;
+                }
+                ('>', true) | (',', false) => break,
+                // group name i.e. `Team: a@example.com;`
+                (':', false) => {
+                    bare.clear();
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        if comment == 0 {
+            angle.as_mut().unwrap_or(&mut bare).push(c);
+        }
+    }
+
+    // text before an angle address is the display name
+    let (name, address) = match angle {
+        Some(address) => (bare.replace('"', ""), address),
+        None => (String::new(), bare),
+    };
+    let address: String = address
+        .trim_end_matches(';')
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    Some((name, address)).filter(|(_, address)| address.contains('@'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn canonicalize_address() {
+        assert_eq!(canonicalize("Bob@Example.COM"), "bob@example.com");
+        assert_eq!(canonicalize("*"), "*");
+        assert_eq!(canonicalize("@Example.COM"), "@example.com");
+
+        // unicode local part
+        assert_eq!(canonicalize("ÖSTEN@example.com"), "östen@example.com");
+        assert_eq!(canonicalize("ΔΙΑ@example.com"), "δια@example.com");
+
+        // full case folding
+        assert_eq!(canonicalize("STRASSE@example.com"), "strasse@example.com");
+        assert_eq!(canonicalize("Straße@example.com"), "strasse@example.com");
+        assert_eq!(canonicalize("ﬁle@example.com"), "file@example.com");
+        assert_eq!(
+            canonicalize("ΟΔΟΣ@example.com"),
+            canonicalize("οδος@example.com")
+        );
+
+        // unicode domain becomes punycode
+        assert_eq!(
+            canonicalize("bob@Bücher.example"),
+            "bob@xn--bcher-kva.example"
+        );
+        assert_eq!(
+            canonicalize("bob@XN--BCHER-KVA.example"),
+            "bob@xn--bcher-kva.example"
+        );
+        assert_eq!(
+            canonicalize("用户@例子.测试"),
+            "用户@xn--fsqu00a.xn--0zwm56d"
+        );
+    }
+
+    #[test]
+    fn parse_smtp_address() {
+        let address = |args: &[&str]| smtp_address(args);
+        assert_eq!(address(&["<bob@example.org>"]).unwrap(), "bob@example.org");
+        assert_eq!(
+            address(&["<bob@example.org>", "SIZE=1024", "BODY=8BITMIME"])
+                .unwrap(),
+            "bob@example.org"
+        );
+        assert_eq!(
+            address(&["<bob@example.org> SIZE=1024"]).unwrap(),
+            "bob@example.org"
+        );
+        assert_eq!(address(&["bob@example.org"]).unwrap(), "bob@example.org");
+        assert_eq!(
+            address(&["<@relay.example:bob@example.org>"]).unwrap(),
+            "bob@example.org"
+        );
+        assert_eq!(address(&["<>"]).unwrap(), "");
+        assert_eq!(address(&["<>", "RET=HDRS"]).unwrap(), "");
+        assert_eq!(address(&[]), None);
+    }
+
+    #[test]
+    fn parse_list_headers() {
+        let id = |value| list_id(value).unwrap_or_default();
+        assert_eq!(
+            id("Developers <dev.lists.example.org>"),
+            "dev.lists.example.org"
+        );
+        assert_eq!(
+            id("\"Dev <list>\" <dev.lists.example.org>"),
+            "dev.lists.example.org"
+        );
+        assert_eq!(id("<Dev.Lists.Example.org>"), "Dev.Lists.Example.org");
+        assert_eq!(id("dev.lists.example.org"), "dev.lists.example.org");
+        assert_eq!(list_id("Developers"), None);
+        assert_eq!(list_id("<>"), None);
+
+        let post = |value| list_post_address(value).unwrap_or_default();
+        assert_eq!(
+            post("<mailto:dev@lists.example.org>"),
+            "dev@lists.example.org"
+        );
+        assert_eq!(
+            post(
+                "<https://lists.example.org/post>, \
+                 <MAILTO:dev@lists.example.org?subject=post>"
+            ),
+            "dev@lists.example.org"
+        );
+        assert_eq!(list_post_address("NO"), None);
+        assert_eq!(list_post_address("NO (posting not allowed)"), None);
+        assert_eq!(list_post_address("<https://lists.example.org/post>"), None);
+    }
+
+    #[test]
+    fn parse_header_address() {
+        let parse = |value| header_address(value);
+        let jane = Some("jane@example.com".to_owned());
+        assert_eq!(parse("jane@example.com"), jane);
+        assert_eq!(parse(" Jane Doe <jane@example.com>"), jane);
+        assert_eq!(
+            parse("\"Doe, Jane <boss>\" <jane@example.com>, bob@example.org"),
+            jane
+        );
+        assert_eq!(parse("jane@example.com (Jane <x@example.org>)"), jane);
+        assert_eq!(parse("\"a\\\"b\" <\r\n jane@example.com>"), jane);
+        assert_eq!(parse("Team: jane@example.com;"), jane);
+        assert_eq!(parse("<>"), None);
+        assert_eq!(parse("undisclosed-recipients:;"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn parse_display_name() {
+        let parse = |value| header_display_name(value);
+        let jane = Some("Doe, Jane".to_owned());
+        assert_eq!(parse("\"Doe, Jane\" <jane@example.com>"), jane);
+        assert_eq!(parse(" \"Doe,\r\n Jane\" (CFO) <jane@example.com>"), jane);
+        assert_eq!(parse("Team: \"Doe, Jane\" <jane@example.com>;"), jane);
+        assert_eq!(
+            parse("Jane <jane@example.com>, Bob <bob@example.org>"),
+            Some("Jane".to_owned())
+        );
+        assert_eq!(parse("<jane@example.com>"), None);
+        assert_eq!(parse("jane@example.com (Jane Doe)"), None);
+        assert_eq!(parse("Jane Doe"), None);
+
+        let matches = |name| display_name_matches(name, "jane.cfo@example.com");
+        assert!(matches("Jane CFO"));
+        assert!(matches("CFO, Jane"));
+        assert!(matches("jane_cfo"));
+        assert!(matches("Jane.CFO@Example.com"));
+        assert!(matches("Jane (jane.cfo@example.com)"));
+        assert!(!matches("Jane"));
+        assert!(!matches("Jane CFO Assistant"));
+        assert!(!matches("jane.cfo@example.org"));
+        assert!(!matches("..."));
+
+        // a single word never matches, only the address does
+        for contact in ["support", "info", "bob"] {
+            let address = format!("{}@example.com", contact);
+            let matches = |name| display_name_matches(name, &address);
+            assert!(!matches("Support"));
+            assert!(!matches("Info"));
+            assert!(!matches("Bob"));
+            assert!(matches(&format!("Team <{}>", address)));
+        }
+    }
+
+    #[test]
+    fn decode_sender() {
+        let all = Decoders {
+            srs: true,
+            batv: true,
+            verp: true,
+        };
+        let decode = |address| all.decode(address);
+        let bob = Some("bob@orig.example".to_owned());
+        assert_eq!(decode("srs0=hhh=tt=orig.example=bob@fwd.example"), bob);
+        assert_eq!(decode("SRS0+hhh=tt=orig.example=bob@fwd.example"), bob);
+        assert_eq!(
+            decode("srs1=hhh=fwd1.example==hhh=tt=orig.example=bob@fwd2.example"),
+            bob
+        );
+        assert_eq!(decode("prvs=1234abcd=bob@orig.example"), bob);
+        // BATV address forwarded with SRS
+        assert_eq!(
+            decode("srs0=hhh=tt=orig.example=prvs=1234abcd=bob@fwd.example"),
+            bob
+        );
+        assert_eq!(
+            decode("list-bounces+jane=example.com@lists.example"),
+            Some("list-bounces@lists.example".to_owned())
+        );
+
+        assert_eq!(decode("bob@orig.example"), None);
+        assert_eq!(decode("srs0=hhh=tt=orig.example@fwd.example"), None);
+        assert_eq!(decode("srs2=hhh=tt=orig.example=bob@fwd.example"), None);
+        assert_eq!(decode("prvs=1234abcd=@orig.example"), None);
+        assert_eq!(decode("+bob=example.com@lists.example"), None);
+
+        // disabled decoders
+        let srs = Decoders {
+            srs: true,
+            ..Decoders::NONE
+        };
+        assert_eq!(srs.decode("prvs=1234abcd=bob@orig.example"), None);
+        assert_eq!(Decoders::NONE.decode("srs0=h=t=d=bob@f.example"), None);
+
+        // same decoders as the configuration defaults
+        let defaults = Decoders {
+            srs: true,
+            batv: true,
+            verp: false,
+        };
+        assert_eq!(Decoders::default(), defaults);
+
+        let options = AddressOptions::new(
+            Some("+".to_owned()),
+            true,
+            SenderMatch::Envelope,
+            all,
+        );
+        assert_eq!(
+            options.sender_variants("prvs=1234abcd=bob+news@orig.example"),
+            vec![
+                "prvs=1234abcd=bob+news@orig.example",
+                "prvs=1234abcd=bob@orig.example",
+                "bob+news@orig.example",
+                "bob@orig.example",
+            ]
+        );
+    }
+
+    #[test]
+    fn strip_detail_part() {
+        assert_eq!(
+            strip_detail("alice+shop@example.com", "+"),
+            Some("alice@example.com".to_owned())
+        );
+        assert_eq!(
+            strip_detail("alice-shop+x@example.com", "+-"),
+            Some("alice@example.com".to_owned())
+        );
+        assert_eq!(strip_detail("alice+shop", "+"), Some("alice".to_owned()));
+
+        assert_eq!(strip_detail("alice@example.com", "+"), None);
+        assert_eq!(strip_detail("+shop@example.com", "+"), None);
+        // delimiter in domain part is not a detail
+        assert_eq!(strip_detail("alice@my-example.com", "-"), None);
+    }
+
+    #[test]
+    fn address_variants() {
+        let options = AddressOptions::default();
+        assert_eq!(
+            options.recipient_variants("alice+shop@example.com"),
+            vec!["alice+shop@example.com"]
+        );
+
+        let delimiter = Some("+".to_owned());
+        let envelope = SenderMatch::Envelope;
+        let decoders = Decoders::default();
+        let options =
+            AddressOptions::new(delimiter.clone(), false, envelope, decoders);
+        assert_eq!(
+            options.recipient_variants("alice+shop@example.com"),
+            vec!["alice+shop@example.com", "alice@example.com"]
+        );
+        assert_eq!(
+            options.sender_variants("bob+news@example.org"),
+            vec!["bob+news@example.org"]
+        );
+
+        let options =
+            AddressOptions::new(delimiter, true, envelope, decoders);
+        assert_eq!(
+            options.sender_variants("bob+news@example.org"),
+            vec!["bob+news@example.org", "bob@example.org"]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Postkeeper sender authentication from `Authentication-Results` headers
+
+use crate::address::{canonical_domain, domain_of, is_subdomain_of};
+
+/// Domains a message has a passing SPF or DKIM result for, collected from
+/// `Authentication-Results` headers (RFC 8601) of trusted authserv-ids
+///
+/// EXAMPLE with trusted authserv-id `mx.example.com`:
+///  `mx.example.com; spf=pass smtp.mailfrom=bounce@esp.example;
+///   dkim=pass header.d=shop.example` authenticates `esp.example` and
+///  `shop.example`, the same header of any other authserv-id is ignored
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Authentication {
+    domains: Vec<String>,
+}
+
+impl Authentication {
+    /// adds the passing SPF and DKIM domains of a header value, ignores the
+    /// header if its authserv-id is not in `trusted`
+    pub fn add_header(&mut self, value: &str, trusted: &[String]) {
+        let value = strip_comments(value);
+        let mut results = value.split(';');
+        // authserv-id can be followed by a version
+        let authserv_id = match results.next() {
+            Some(id) => id.split_whitespace().next().unwrap_or_default(),
+            None => return,
+        };
+        if !trusted.iter().any(|id| id.eq_ignore_ascii_case(authserv_id)) {
+            log::debug!("Ignoring results of untrusted `{}`", authserv_id);
+            return;
+        }
+
+        for result in results {
+            let mut tokens = result.split_whitespace();
+            let properties: &[&str] = match tokens.next() {
+                Some(method) if method.eq_ignore_ascii_case("spf=pass") => {
+                    &["smtp.mailfrom"]
+                }
+                Some(method) if method.eq_ignore_ascii_case("dkim=pass") => {
+                    &["header.d", "header.i"]
+                }
+                _ => continue,
+            };
+            let domain = tokens
+                .filter_map(|token| token.split_once('='))
+                .find(|(name, _)| {
+                    properties.iter().any(|p| name.eq_ignore_ascii_case(p))
+                })
+                .map(|(_, value)| value.trim_matches('"'))
+                .map(|value| domain_of(value).unwrap_or(value));
+            if let Some(domain) = domain.filter(|d| !d.is_empty()) {
+                log::debug!("Authenticated domain `{}`", domain);
+                self.domains.push(canonical_domain(domain));
+            }
+        }
+    }
+
+    /// true if the domain of `address` is aligned with an authenticated
+    /// domain, either the same domain or one is a subdomain of the other
+    pub fn is_aligned(&self, address: &str) -> bool {
+        let domain = match domain_of(address) {
+            Some(domain) => canonical_domain(domain),
+            None => return false,
+        };
+        self.domains.iter().any(|authenticated| {
+            *authenticated == domain
+                || is_subdomain_of(&domain, authenticated)
+                || is_subdomain_of(authenticated, &domain)
+        })
+    }
+}
+
+// header value without `(...)` comments, comments can be nested
+fn strip_comments(value: &str) -> String {
+    let mut depth = 0;
+    value
+        .chars()
+        .filter(|c| {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 0 => {
+                    depth -= 1;
+                    return false;
+                }
+                _ => {}
+            }
+            depth == 0
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authentication(headers: &[&str]) -> Authentication {
+        let trusted = vec!["mx.example.com".to_owned()];
+        let mut authentication = Authentication::default();
+        for header in headers {
+            authentication.add_header(header, &trusted);
+        }
+        authentication
+    }
+
+    #[test]
+    fn spf_and_dkim_results() {
+        let auth = authentication(&[
+            "mx.example.com 1; spf=pass (sender is permitted) \
+             smtp.mailfrom=bounce@esp.example; \
+             dkim=pass (2048-bit key) header.d=Shop.example header.s=s1",
+        ]);
+        assert!(auth.is_aligned("bounce@esp.example"));
+        assert!(auth.is_aligned("news@shop.example"));
+        assert!(auth.is_aligned("news@mail.shop.example"));
+        assert!(!auth.is_aligned("ceo@partner.example"));
+        assert!(!auth.is_aligned(""));
+
+        let auth = authentication(&[
+            "MX.example.com; dkim=pass header.i=@mail.partner.example",
+        ]);
+        assert!(auth.is_aligned("ceo@partner.example"));
+        assert!(!auth.is_aligned("ceo@notpartner.example"));
+    }
+
+    #[test]
+    fn failed_and_untrusted_results() {
+        let auth = authentication(&[
+            "mx.example.com; spf=fail smtp.mailfrom=ceo@partner.example; \
+             dkim=none; dkim=pass header.s=s1",
+            "mx.evil.example; spf=pass smtp.mailfrom=ceo@partner.example",
+            "mx.example.com (spf=pass smtp.mailfrom=partner.example); none",
+        ]);
+        assert_eq!(auth, Authentication::default());
+    }
+}
@@ -1,10 +1,13 @@
 //! Postkeeper milter and daemon configuration
 
+use crate::address::{AddressOptions, Decoders, SenderMatch};
 use crate::consts::{arg, default};
+use crate::header::HeaderTemplate;
 use crate::prelude::*;
 use clap::ArgMatches;
-use ini::Ini;
+use ini::{Ini, Properties};
 use once_cell::sync::OnceCell;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
@@ -28,7 +31,20 @@ pub struct Config {
     pid_file: PathBuf,
     log_file: PathBuf,
     log_level: log::Level,
-    on_block_action: milter::Status,
+    on_block_action: BlockAction,
+    reject_at_rcpt: bool,
+    address_options: AddressOptions,
+    require_authentication: bool,
+    authserv_ids: Vec<String>,
+    authenticated_senders: AuthenticatedPolicy,
+    on_outbound_block: OutboundAction,
+    null_sender: NullSenderPolicy,
+    on_impersonation: ImpersonationAction,
+    reply: Option<Reply>,
+    replies: HashMap<String, Reply>,
+    allow_header: HeaderTemplate,
+    block_header: HeaderTemplate,
+    subject_prefix: Option<String>,
     reload_interval: Duration,
     allow_map: PathBuf,
     block_map: PathBuf,
@@ -79,16 +95,8 @@ impl Config {
             conf.group = Some(group);
         }
 
-        if let Some(on_block_action) =
-            matches
-                .value_of(arg::ON_BLOCK_ACTION)
-                .map(|on_block_action| match on_block_action {
-                    "discard" => milter::Status::Discard,
-                    "continue" => milter::Status::Continue,
-                    _ => milter::Status::Reject,
-                })
-        {
-            conf.on_block_action = on_block_action;
+        if let Some(on_block_action) = matches.value_of(arg::ON_BLOCK_ACTION) {
+            conf.on_block_action = BlockAction::parse(on_block_action)?;
         }
 
         if matches.is_present(arg::VERBOSE) {
@@ -167,10 +175,80 @@ impl Config {
         self.log_level
     }
 
-    pub fn on_block_action(&self) -> milter::Status {
+    pub fn on_block_action(&self) -> BlockAction {
         self.on_block_action
     }
 
+    pub fn reject_at_rcpt(&self) -> bool {
+        self.reject_at_rcpt
+    }
+
+    /// options applied to recipient and sender addresses for map lookup
+    pub fn address_options(&self) -> &AddressOptions {
+        &self.address_options
+    }
+
+    /// true if allow list matches only count for authenticated senders
+    pub fn require_authentication(&self) -> bool {
+        self.require_authentication
+    }
+
+    /// authserv-ids whose `Authentication-Results` headers are trusted
+    pub fn authserv_ids(&self) -> &[String] {
+        &self.authserv_ids
+    }
+
+    /// how messages of SASL authenticated clients are checked
+    pub fn authenticated_senders(&self) -> AuthenticatedPolicy {
+        self.authenticated_senders
+    }
+
+    /// action for recipients an authenticated local sender has blocked
+    /// with `authenticated_senders = outbound`
+    pub fn on_outbound_block(&self) -> OutboundAction {
+        self.on_outbound_block
+    }
+
+    /// how messages with the null sender `<>` are checked
+    pub fn null_sender(&self) -> NullSenderPolicy {
+        self.null_sender
+    }
+
+    /// action for messages whose `From:` display name impersonates an allow
+    /// listed contact of a recipient
+    pub fn on_impersonation(&self) -> ImpersonationAction {
+        self.on_impersonation
+    }
+
+    /// SMTP reply for a blocked message or recipient
+    /// returns named reply (from `[reply.<name>]` section) if given and
+    /// configured, otherwise default reply from general section
+    /// None means libmilter default reply is used
+    pub fn block_reply(&self, name: Option<&str>) -> Option<&Reply> {
+        if let Some(name) = name {
+            match self.replies.get(name) {
+                Some(reply) => return Some(reply),
+                None => log::warn!("Reply `{}` is not configured", name),
+            }
+        }
+        self.reply.as_ref()
+    }
+
+    /// header added to messages allowed for every recipient
+    pub fn allow_header(&self) -> &HeaderTemplate {
+        &self.allow_header
+    }
+
+    /// header added to blocked messages with `tag` action
+    pub fn block_header(&self) -> &HeaderTemplate {
+        &self.block_header
+    }
+
+    /// prefix added to Subject of blocked messages with `tag` action
+    pub fn subject_prefix(&self) -> Option<&str> {
+        self.subject_prefix.as_deref()
+    }
+
     pub fn reload_interval(&self) -> Duration {
         self.reload_interval
     }
@@ -180,7 +258,7 @@ impl Config {
     /// to allow only define variable that require a change
     /// NOTE:
     /// Errors if cannot read config file
-    fn from_conf_file(path: impl AsRef<Path>) -> Result<Self> {
+    pub(crate) fn from_conf_file(path: impl AsRef<Path>) -> Result<Self> {
         println!("loading config from path {:?}", path.as_ref());
 
         let ini = Ini::load_from_file(path)?;
@@ -231,12 +309,126 @@ impl Config {
 
         let on_block_action = section
             .get("on_block_action")
-            .map(|on_block_action| match on_block_action {
-                "discard" => milter::Status::Discard,
-                "continue" => milter::Status::Continue,
-                _ => default::MILTER_STATUS,
-            })
-            .unwrap_or_else(|| default::MILTER_STATUS);
+            .map(BlockAction::parse)
+            .transpose()?
+            .unwrap_or(default::ON_BLOCK_ACTION);
+
+        let reject_at_rcpt =
+            parse_bool(section, "reject_at_rcpt", default::REJECT_AT_RCPT)?;
+
+        let recipient_delimiter = section
+            .get("recipient_delimiter")
+            .map(str::trim)
+            .filter(|delimiter| !delimiter.is_empty())
+            .map(String::from);
+        let is_invalid = |c: char| c == '@' || c.is_whitespace();
+        if let Some(delimiter) =
+            recipient_delimiter.as_deref().filter(|d| d.contains(is_invalid))
+        {
+            return Err(Error::config_err(format!(
+                "Error parsing recipient_delimiter, invalid value {:?}",
+                delimiter
+            )));
+        }
+        let strip_sender_detail = parse_bool(
+            section,
+            "strip_sender_detail",
+            default::STRIP_SENDER_DETAIL,
+        )?;
+        let sender_match = match section.get("sender_match") {
+            Some(value) => SenderMatch::parse(value).ok_or_else(|| {
+                Error::config_err(format!(
+                    "Error parsing sender_match, invalid value {:?}",
+                    value
+                ))
+            })?,
+            None => default::SENDER_MATCH,
+        };
+        let decoders = Decoders {
+            srs: parse_bool(section, "decode_srs", default::DECODE_SRS)?,
+            batv: parse_bool(section, "decode_batv", default::DECODE_BATV)?,
+            verp: parse_bool(section, "collapse_verp", default::COLLAPSE_VERP)?,
+        };
+        let address_options = AddressOptions::new(
+            recipient_delimiter,
+            strip_sender_detail,
+            sender_match,
+            decoders,
+        );
+
+        let require_authentication = parse_bool(
+            section,
+            "require_authentication",
+            default::REQUIRE_AUTHENTICATION,
+        )?;
+        let authserv_ids: Vec<String> = section
+            .get("authserv_id")
+            .unwrap_or_default()
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|id| !id.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        if require_authentication && authserv_ids.is_empty() {
+            return Err(Error::config_err(
+                "Error parsing authserv_id, required with \
+                 require_authentication",
+            ));
+        }
+
+        let authenticated_senders = section
+            .get("authenticated_senders")
+            .map(AuthenticatedPolicy::parse)
+            .transpose()?
+            .unwrap_or(default::AUTHENTICATED_SENDERS);
+
+        let on_outbound_block = section
+            .get("on_outbound_block")
+            .map(OutboundAction::parse)
+            .transpose()?
+            .unwrap_or(default::ON_OUTBOUND_BLOCK);
+
+        let null_sender = section
+            .get("null_sender")
+            .map(NullSenderPolicy::parse)
+            .transpose()?
+            .unwrap_or(default::NULL_SENDER);
+
+        let on_impersonation = section
+            .get("on_impersonation")
+            .map(ImpersonationAction::parse)
+            .transpose()?
+            .unwrap_or(default::ON_IMPERSONATION);
+
+        // default reply is defined in general section, named replies in
+        // `[reply.<name>]` sections
+        let reply = Reply::from_section(section, None)?;
+        let mut replies = HashMap::new();
+        for (name, section) in ini.iter() {
+            let name = match name.and_then(|n| n.strip_prefix("reply.")) {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Some(reply) = Reply::from_section(section, Some(name))? {
+                replies.insert(name.to_owned(), reply);
+            }
+        }
+
+        let allow_header = header_template(
+            section,
+            "allow_header",
+            default::ALLOW_HEADER,
+        )?;
+        let block_header = header_template(
+            section,
+            "block_header",
+            default::BLOCK_HEADER,
+        )?;
+
+        let subject_prefix = section
+            .get("subject_prefix")
+            .map(str::trim)
+            .filter(|prefix| !prefix.is_empty())
+            .map(String::from);
 
         let reload_interval = section
             .get("reload_interval")
@@ -258,11 +450,317 @@ impl Config {
             group,
             log_level,
             on_block_action,
+            reject_at_rcpt,
+            address_options,
+            require_authentication,
+            authserv_ids,
+            authenticated_senders,
+            on_outbound_block,
+            null_sender,
+            on_impersonation,
+            reply,
+            replies,
+            allow_header,
+            block_header,
+            subject_prefix,
             reload_interval,
         })
     }
 }
 
+/// Action taken on a message when the sender is in block list
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockAction {
+    /// reject with a permanent 5xx failure, sender MTA is notified
+    Reject,
+    /// reject with a temporary 4xx failure, sender MTA retries later
+    Tempfail,
+    /// accept and discard the message, sender MTA is not notified
+    Discard,
+    /// accept and hold the message in MTA quarantine (postfix hold queue)
+    Quarantine,
+    /// accept and mark the message with a header (and Subject prefix)
+    Tag,
+    /// continue processing message as normal
+    Continue,
+}
+
+impl BlockAction {
+    /// parses config value, errors on unknown values
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "reject" => Ok(BlockAction::Reject),
+            "tempfail" => Ok(BlockAction::Tempfail),
+            "discard" => Ok(BlockAction::Discard),
+            "quarantine" => Ok(BlockAction::Quarantine),
+            "tag" => Ok(BlockAction::Tag),
+            "continue" => Ok(BlockAction::Continue),
+            _ => Err(Error::config_err(format!(
+                "Error parsing on_block_action, invalid value {:?}",
+                value
+            ))),
+        }
+    }
+
+    /// milter status returned for a blocked message or recipient
+    pub fn status(self) -> milter::Status {
+        match self {
+            BlockAction::Reject => milter::Status::Reject,
+            BlockAction::Tempfail => milter::Status::Tempfail,
+            BlockAction::Discard => milter::Status::Discard,
+            BlockAction::Quarantine
+            | BlockAction::Tag
+            | BlockAction::Continue => milter::Status::Continue,
+        }
+    }
+
+    /// true if recipients who blocked the sender are removed from a message
+    /// that has other recipients too. `continue` keeps them. `tempfail`
+    /// fails the whole message instead as a message cannot be temporarily
+    /// failed for some recipients only after DATA, use `reject_at_rcpt` for
+    /// that. `tag` keeps them and adds the block header for them.
+    /// `quarantine` keeps them and holds the whole message, blocked mail is
+    /// never lost.
+    pub fn removes_blocked_recipients(self) -> bool {
+        match self {
+            BlockAction::Reject | BlockAction::Discard => true,
+            BlockAction::Tempfail
+            | BlockAction::Quarantine
+            | BlockAction::Tag
+            | BlockAction::Continue => false,
+        }
+    }
+}
+
+/// How messages sent by SASL authenticated clients (`{auth_authen}`) are
+/// checked, i.e. local users sending through submission
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthenticatedPolicy {
+    /// check recipient lists like any other message
+    Check,
+    /// skip every check, recipient lists do not apply to local users
+    Bypass,
+    /// check the block list of the sender against each recipient instead,
+    /// local users can not send mail to addresses they blocked
+    Outbound,
+}
+
+impl AuthenticatedPolicy {
+    /// parses config value, errors on unknown values
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "check" => Ok(AuthenticatedPolicy::Check),
+            "bypass" => Ok(AuthenticatedPolicy::Bypass),
+            "outbound" => Ok(AuthenticatedPolicy::Outbound),
+            _ => Err(Error::config_err(format!(
+                "Error parsing authenticated_senders, invalid value {:?}",
+                value
+            ))),
+        }
+    }
+}
+
+/// Action for a recipient an authenticated local sender has in block list
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutboundAction {
+    /// reject the recipient at `RCPT TO` stage
+    Reject,
+    /// deliver and add `block_header` to the message
+    Warn,
+}
+
+impl OutboundAction {
+    /// parses config value, errors on unknown values
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "reject" => Ok(OutboundAction::Reject),
+            "warn" => Ok(OutboundAction::Warn),
+            _ => Err(Error::config_err(format!(
+                "Error parsing on_outbound_block, invalid value {:?}",
+                value
+            ))),
+        }
+    }
+}
+
+/// How messages with the null sender `<>` (bounces, DSNs) are checked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NullSenderPolicy {
+    /// check recipient lists, `<>` entries match the null sender
+    Check,
+    /// skip every check, bounces are always delivered
+    Bypass,
+    /// block unless a list of the recipient has an entry for the null
+    /// sender, a `<>` allow entry keeps the bounces of a recipient
+    Block,
+}
+
+impl NullSenderPolicy {
+    /// parses config value, errors on unknown values
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "check" => Ok(NullSenderPolicy::Check),
+            "bypass" => Ok(NullSenderPolicy::Bypass),
+            "block" => Ok(NullSenderPolicy::Block),
+            _ => Err(Error::config_err(format!(
+                "Error parsing null_sender, invalid value {:?}",
+                value
+            ))),
+        }
+    }
+}
+
+/// Action for a message whose `From:` display name matches an allow listed
+/// contact of a recipient while its address is not one of the contacts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImpersonationAction {
+    /// only log the impersonation, allow matches are ignored
+    Continue,
+    /// add `block_header` and `subject_prefix` to the message
+    Tag,
+    /// reject the message
+    Reject,
+    /// put the message on hold in MTA quarantine
+    Quarantine,
+}
+
+impl ImpersonationAction {
+    /// parses config value, errors on unknown values
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "continue" => Ok(ImpersonationAction::Continue),
+            "tag" => Ok(ImpersonationAction::Tag),
+            "reject" => Ok(ImpersonationAction::Reject),
+            "quarantine" => Ok(ImpersonationAction::Quarantine),
+            _ => Err(Error::config_err(format!(
+                "Error parsing on_impersonation, invalid value {:?}",
+                value
+            ))),
+        }
+    }
+}
+
+/// SMTP reply sent to the client when a message or recipient is rejected
+/// i.e. `550 5.7.1 Recipient does not accept mail from you`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    code: String,
+    status: Option<String>,
+    text: Option<String>,
+}
+
+impl Reply {
+    /// SMTP reply code i.e. `550`
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// enhanced status code i.e. `5.7.1`
+    pub fn status(&self) -> Option<&str> {
+        self.status.as_deref()
+    }
+
+    /// human readable reply text
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    /// same reply as a temporary failure, `550 5.7.1` becomes `450 4.7.1`
+    pub fn to_tempfail(&self) -> Self {
+        let tempfail = |value: &str| format!("4{}", &value[1..]);
+        Self {
+            code: tempfail(&self.code),
+            status: self.status.as_deref().map(tempfail),
+            text: self.text.clone(),
+        }
+    }
+
+    // Parses the reply from `reply_code`, `reply_status` and `reply_text` keys
+    // of general section or `code`, `status` and `text` keys of a named
+    // `[reply.<name>]` section
+    // returns None if none of the keys are defined, code defaults to `550`
+    // Errors if code is not a 5xx code or status does not match the code
+    fn from_section(
+        section: &Properties,
+        name: Option<&str>,
+    ) -> Result<Option<Self>> {
+        let (prefix, label) = match name {
+            Some(name) => ("", format!("[reply.{}] ", name)),
+            None => ("reply_", String::new()),
+        };
+        let get = |key: &str| section.get(format!("{}{}", prefix, key));
+        let invalid = |key: &str, value: &str| {
+            Error::config_err(format!(
+                "Error parsing {}{}{}, invalid value {:?}",
+                label, prefix, key, value
+            ))
+        };
+
+        let code = get("code");
+        let status = get("status");
+        let text = get("text");
+
+        if code.is_none() && status.is_none() && text.is_none() {
+            return Ok(None);
+        }
+
+        let code = code.unwrap_or(default::REPLY_CODE);
+        if code.len() != 3
+            || !code.starts_with('5')
+            || !code.chars().all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid("code", code));
+        }
+
+        if let Some(status) = status {
+            let parts: Vec<&str> = status.split('.').collect();
+            let is_valid = parts.len() == 3
+                && parts[0] == &code[..1]
+                && parts.iter().all(|part| {
+                    (1..=3).contains(&part.len())
+                        && part.chars().all(|c| c.is_ascii_digit())
+                });
+            if !is_valid {
+                return Err(invalid("status", status));
+            }
+        }
+
+        Ok(Some(Self {
+            code: code.to_owned(),
+            status: status.map(String::from),
+            text: text.map(String::from),
+        }))
+    }
+}
+
+// Parses a boolean value of given key, returns default if key is not defined
+// accepts `yes`/`no`, `true`/`false`, `on`/`off` and `1`/`0`
+fn parse_bool(section: &Properties, key: &str, default: bool) -> Result<bool> {
+    match section.get(key).map(str::to_lowercase).as_deref() {
+        None => Ok(default),
+        Some("yes") | Some("true") | Some("on") | Some("1") => Ok(true),
+        Some("no") | Some("false") | Some("off") | Some("0") => Ok(false),
+        Some(value) => Err(Error::config_err(format!(
+            "Error parsing {}, invalid value {:?}",
+            key, value
+        ))),
+    }
+}
+
+// Parses header name from `key` and its value template from `<key>_value`
+// value defaults to `Yes`
+fn header_template(
+    section: &Properties,
+    key: &str,
+    default: &str,
+) -> Result<HeaderTemplate> {
+    let name = section.get(key).unwrap_or(default);
+    let value = section
+        .get(format!("{}_value", key))
+        .unwrap_or(default::HEADER_VALUE);
+    HeaderTemplate::parse(key, name, value)
+}
+
 // Holds config validation state
 #[derive(PartialEq)]
 enum Validation {
@@ -364,7 +862,8 @@ mod tests {
 
         assert_eq!(config.allow_map_path(), &PathBuf::from(default::ALLOW_MAP));
 
-        assert_eq!(config.on_block_action(), milter::Status::Reject);
+        assert_eq!(config.on_block_action(), BlockAction::Reject);
+        assert!(!config.reject_at_rcpt());
         assert_eq!(config.log_level(), log::Level::Error);
 
         assert_eq!(config.block_map_path(), &PathBuf::from(default::BLOCK_MAP));
@@ -411,11 +910,336 @@ mod tests {
         assert_eq!(config.user(), Some("user"));
         assert_eq!(config.group(), Some("group"));
         assert_eq!(config.log_level(), log::Level::Trace);
-        assert_eq!(config.on_block_action(), milter::Status::Discard);
+        assert_eq!(config.on_block_action(), BlockAction::Discard);
 
         assert_eq!(config.validate(), Ok(()))
     }
 
+    #[test]
+    fn custom_config_reject_at_rcpt() {
+        init_logging();
+        let config = Config::from_conf_file("tests/conf.d/reject-at-rcpt.ini")
+            .expect("Custom ini should load");
+
+        assert_eq!(config.on_block_action(), BlockAction::Reject);
+        assert!(config.reject_at_rcpt());
+    }
+
+    #[test]
+    fn custom_config_recipient_delimiter() {
+        init_logging();
+        let config =
+            Config::from_conf_file("tests/conf.d/recipient-delimiter.ini")
+                .expect("Custom ini should load");
+
+        assert_eq!(
+            config.address_options(),
+            &AddressOptions::new(
+                Some("+-".to_owned()),
+                true,
+                SenderMatch::Both,
+                Decoders {
+                    srs: true,
+                    batv: false,
+                    verp: true
+                }
+            )
+        );
+
+        let config = Config::from_conf_file("assets/etc/postkeeper.ini")
+            .expect("Default postkeeper.ini should load");
+        let decoders = Decoders::default();
+        assert_eq!(
+            config.address_options(),
+            &AddressOptions::new(None, false, SenderMatch::Envelope, decoders)
+        );
+
+        let err = Config::from_conf_file(
+            "tests/conf.d/invalid-recipient-delimiter.ini",
+        )
+        .expect_err("Custom ini should not load");
+        assert_eq!(
+            err,
+            Error::config_err(
+                "Error parsing recipient_delimiter, invalid value \"+@\""
+            )
+        );
+    }
+
+    #[test]
+    fn custom_config_require_authentication() {
+        init_logging();
+        let config =
+            Config::from_conf_file("tests/conf.d/require-authentication.ini")
+                .expect("Custom ini should load");
+        assert!(config.require_authentication());
+        assert_eq!(
+            config.authserv_ids(),
+            &["mx.example.com", "mx2.example.com"]
+        );
+
+        let config = Config::from_conf_file("assets/etc/postkeeper.ini")
+            .expect("Default postkeeper.ini should load");
+        assert!(!config.require_authentication());
+        assert!(config.authserv_ids().is_empty());
+
+        let err =
+            Config::from_conf_file("tests/conf.d/invalid-authserv-id.ini")
+                .expect_err("Custom ini should not load");
+        assert_eq!(
+            err,
+            Error::config_err(
+                "Error parsing authserv_id, required with \
+                 require_authentication"
+            )
+        );
+    }
+
+    #[test]
+    fn custom_config_authenticated_senders() {
+        init_logging();
+        let config =
+            Config::from_conf_file("tests/conf.d/authenticated-bypass.ini")
+                .expect("Custom ini should load");
+        assert_eq!(config.authenticated_senders(), AuthenticatedPolicy::Bypass);
+        assert_eq!(config.on_outbound_block(), OutboundAction::Reject);
+
+        let config = Config::from_conf_file("tests/conf.d/outbound-warn.ini")
+            .expect("Custom ini should load");
+        assert_eq!(
+            config.authenticated_senders(),
+            AuthenticatedPolicy::Outbound
+        );
+        assert_eq!(config.on_outbound_block(), OutboundAction::Warn);
+
+        let config = Config::from_conf_file("assets/etc/postkeeper.ini")
+            .expect("Default postkeeper.ini should load");
+        assert_eq!(config.authenticated_senders(), AuthenticatedPolicy::Check);
+
+        let err = Config::from_conf_file(
+            "tests/conf.d/invalid-authenticated-senders.ini",
+        )
+        .expect_err("Custom ini should not load");
+        assert_eq!(
+            err,
+            Error::config_err(
+                "Error parsing authenticated_senders, invalid value \"skip\""
+            )
+        );
+    }
+
+    #[test]
+    fn custom_config_null_sender() {
+        init_logging();
+        let config =
+            Config::from_conf_file("tests/conf.d/null-sender-block.ini")
+                .expect("Custom ini should load");
+        assert_eq!(config.null_sender(), NullSenderPolicy::Block);
+
+        let config =
+            Config::from_conf_file("tests/conf.d/null-sender-bypass.ini")
+                .expect("Custom ini should load");
+        assert_eq!(config.null_sender(), NullSenderPolicy::Bypass);
+
+        let config = Config::from_conf_file("assets/etc/postkeeper.ini")
+            .expect("Default postkeeper.ini should load");
+        assert_eq!(config.null_sender(), NullSenderPolicy::Check);
+
+        let err =
+            Config::from_conf_file("tests/conf.d/invalid-null-sender.ini")
+                .expect_err("Custom ini should not load");
+        assert_eq!(
+            err,
+            Error::config_err(
+                "Error parsing null_sender, invalid value \"drop\""
+            )
+        );
+    }
+
+    #[test]
+    fn custom_config_on_impersonation() {
+        init_logging();
+        let config =
+            Config::from_conf_file("tests/conf.d/on-impersonation-reject.ini")
+                .expect("Custom ini should load");
+        assert_eq!(config.on_impersonation(), ImpersonationAction::Reject);
+
+        let config =
+            Config::from_conf_file("tests/conf.d/on-impersonation-tag.ini")
+                .expect("Custom ini should load");
+        assert_eq!(config.on_impersonation(), ImpersonationAction::Tag);
+
+        let config = Config::from_conf_file("assets/etc/postkeeper.ini")
+            .expect("Default postkeeper.ini should load");
+        assert_eq!(config.on_impersonation(), ImpersonationAction::Continue);
+
+        let err =
+            Config::from_conf_file("tests/conf.d/invalid-on-impersonation.ini")
+                .expect_err("Custom ini should not load");
+        assert_eq!(
+            err,
+            Error::config_err(
+                "Error parsing on_impersonation, invalid value \"drop\""
+            )
+        );
+    }
+
+    #[test]
+    fn custom_config_invalid_sender_match() {
+        init_logging();
+        let err =
+            Config::from_conf_file("tests/conf.d/invalid-sender-match.ini")
+                .expect_err("Custom ini should not load");
+
+        assert_eq!(
+            err,
+            Error::config_err(
+                "Error parsing sender_match, invalid value \"from\""
+            )
+        );
+    }
+
+    #[test]
+    fn custom_config_invalid_bool() {
+        init_logging();
+        let err = Config::from_conf_file("tests/conf.d/invalid-bool.ini")
+            .expect_err("Custom ini should not load");
+
+        assert_eq!(
+            err,
+            Error::config_err(
+                "Error parsing reject_at_rcpt, invalid value \"maybe\""
+            )
+        );
+    }
+
+    #[test]
+    fn custom_config_on_block_action() {
+        init_logging();
+        let config =
+            Config::from_conf_file("tests/conf.d/on-block-tempfail.ini")
+                .expect("Custom ini should load");
+        assert_eq!(config.on_block_action(), BlockAction::Tempfail);
+
+        let config =
+            Config::from_conf_file("tests/conf.d/on-block-quarantine.ini")
+                .expect("Custom ini should load");
+        assert_eq!(config.on_block_action(), BlockAction::Quarantine);
+
+        let config =
+            Config::from_conf_file("tests/conf.d/on-block-continue.ini")
+                .expect("Custom ini should load");
+        assert_eq!(config.on_block_action(), BlockAction::Continue);
+    }
+
+    #[test]
+    fn custom_config_invalid_on_block_action() {
+        init_logging();
+        let err =
+            Config::from_conf_file("tests/conf.d/invalid-on-block-action.ini")
+                .expect_err("Custom ini should not load");
+
+        assert_eq!(
+            err,
+            Error::config_err(
+                "Error parsing on_block_action, invalid value \"bounce\""
+            )
+        );
+    }
+
+    #[test]
+    fn custom_config_tag() {
+        init_logging();
+        let config = Config::from_conf_file("tests/conf.d/on-block-tag.ini")
+            .expect("Custom ini should load");
+
+        assert_eq!(config.on_block_action(), BlockAction::Tag);
+        assert_eq!(config.block_header().name(), "X-Blocked");
+        assert_eq!(config.subject_prefix(), Some("[BLOCKED]"));
+
+        // defaults
+        let config = Config::from_conf_file("assets/etc/postkeeper.ini")
+            .expect("Default postkeeper.ini should load");
+        assert_eq!(config.block_header().name(), default::BLOCK_HEADER);
+        assert_eq!(config.allow_header().name(), default::ALLOW_HEADER);
+        assert_eq!(config.subject_prefix(), None);
+    }
+
+    #[test]
+    fn custom_config_invalid_block_header() {
+        init_logging();
+        let err =
+            Config::from_conf_file("tests/conf.d/invalid-block-header.ini")
+                .expect_err("Custom ini should not load");
+
+        assert_eq!(
+            err,
+            Error::config_err(
+                "Error parsing block_header, invalid value \"X Blocked:\""
+            )
+        );
+    }
+
+    #[test]
+    fn tempfail_reply() {
+        init_logging();
+        let config = Config::from_conf_file("tests/conf.d/replies.ini")
+            .expect("Custom ini should load");
+
+        let reply = config.block_reply(None).expect("default reply");
+        let reply = reply.to_tempfail();
+        assert_eq!(reply.code(), "450");
+        assert_eq!(reply.status(), Some("4.7.1"));
+        assert_eq!(
+            reply.text(),
+            Some("Recipient does not accept mail from you")
+        );
+    }
+
+    #[test]
+    fn custom_config_replies() {
+        init_logging();
+        let config = Config::from_conf_file("tests/conf.d/replies.ini")
+            .expect("Custom ini should load");
+
+        let reply = config.block_reply(None).expect("default reply");
+        assert_eq!(reply.code(), "550");
+        assert_eq!(reply.status(), Some("5.7.1"));
+        assert_eq!(
+            reply.text(),
+            Some("Recipient does not accept mail from you")
+        );
+
+        let reply = config.block_reply(Some("policy")).expect("named reply");
+        assert_eq!(reply.code(), "554");
+        assert_eq!(reply.status(), None);
+        assert_eq!(reply.text(), Some("Rejected by site policy"));
+
+        // unknown named reply falls back to the default reply
+        let reply = config.block_reply(Some("unknown")).expect("default reply");
+        assert_eq!(reply.code(), "550");
+
+        // no reply configured, libmilter default is used
+        let config = Config::from_conf_file("assets/etc/postkeeper.ini")
+            .expect("Default postkeeper.ini should load");
+        assert_eq!(config.block_reply(None), None);
+        assert_eq!(config.block_reply(Some("policy")), None);
+    }
+
+    #[test]
+    fn custom_config_invalid_reply() {
+        init_logging();
+        let err = Config::from_conf_file("tests/conf.d/invalid-reply.ini")
+            .expect_err("Custom ini should not load");
+
+        assert_eq!(
+            err,
+            Error::config_err(
+                "Error parsing [reply.soft] status, invalid value \"4.7.1\""
+            )
+        );
+    }
+
     #[test]
     fn custom_config_invalid_allow_map() {
         init_logging();
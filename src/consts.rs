@@ -1,7 +1,9 @@
 pub const NAME: &str = "PostKeeper";
 pub const MACRO_RECPT_ADDR: &str = "{rcpt_addr}";
 pub const MACRO_SENDER_ADDR: &str = "{mail_addr}";
-pub const POSTKEEPER_HEADER: &str = "X-Postkeeper-Allow";
+pub const MACRO_AUTH_AUTHEN: &str = "{auth_authen}";
+/// null sender of bounces and DSNs, in map values and `MAIL FROM:<>`
+pub const NULL_SENDER: &str = "<>";
 
 pub mod arg {
     pub const ALLOW_MAP: &str = "allow-map";
@@ -28,5 +30,26 @@ pub mod default {
     pub const PIDFILE: &str = "/var/run/postkeeper/postkeeper.pid";
     pub const SOCKET: &str = "inet:11210@localhost";
     pub const RELOAD_INTERVAL: &str = "60";
-    pub const MILTER_STATUS: milter::Status = milter::Status::Reject;
+    pub const ON_BLOCK_ACTION: crate::config::BlockAction =
+        crate::config::BlockAction::Reject;
+    pub const REJECT_AT_RCPT: bool = false;
+    pub const STRIP_SENDER_DETAIL: bool = false;
+    pub const DECODE_SRS: bool = true;
+    pub const DECODE_BATV: bool = true;
+    pub const COLLAPSE_VERP: bool = false;
+    pub const REQUIRE_AUTHENTICATION: bool = false;
+    pub const AUTHENTICATED_SENDERS: crate::config::AuthenticatedPolicy =
+        crate::config::AuthenticatedPolicy::Check;
+    pub const ON_OUTBOUND_BLOCK: crate::config::OutboundAction =
+        crate::config::OutboundAction::Reject;
+    pub const NULL_SENDER: crate::config::NullSenderPolicy =
+        crate::config::NullSenderPolicy::Check;
+    pub const ON_IMPERSONATION: crate::config::ImpersonationAction =
+        crate::config::ImpersonationAction::Continue;
+    pub const SENDER_MATCH: crate::address::SenderMatch =
+        crate::address::SenderMatch::Envelope;
+    pub const REPLY_CODE: &str = "550";
+    pub const ALLOW_HEADER: &str = "X-Postkeeper-Allow";
+    pub const BLOCK_HEADER: &str = "X-Postkeeper-Block";
+    pub const HEADER_VALUE: &str = "Yes";
 }
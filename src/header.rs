@@ -0,0 +1,144 @@
+//! Postkeeper decision header implementation
+
+use crate::prelude::*;
+
+/// placeholders that can be used in a header value template
+const PLACEHOLDERS: [&str; 5] =
+    ["list", "entry", "recipient", "sender", "timestamp"];
+
+/// A header Postkeeper adds to a message, name and a value template
+///
+/// EXAMPLE value templates:
+///  `Yes`                              bare flag (default)
+///  `list={list}; entry={entry}`       `list=allow; entry=@example.com`
+///  `{recipient} at {timestamp}`       `bob@example.com at 1700000000`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderTemplate {
+    name: String,
+    value: String,
+}
+
+/// Values of a single decision used to render a header value
+#[derive(Debug)]
+pub struct HeaderFields<'a> {
+    /// `allow` or `block`
+    pub list: &'a str,
+    /// matched map entry
+    pub entry: String,
+    /// recipient the decision was made for, empty if the message has more
+    /// recipients as each of them would see it
+    pub recipient: &'a str,
+    /// envelope sender
+    pub sender: &'a str,
+    /// seconds since unix epoch
+    pub timestamp: u64,
+}
+
+impl HeaderTemplate {
+    /// parses header name and value template of config `key`
+    /// Errors if name is not a valid header name or value has an unknown
+    /// placeholder
+    pub fn parse(key: &str, name: &str, value: &str) -> Result<Self> {
+        let invalid = |key: &str, value: &str| {
+            Error::config_err(format!(
+                "Error parsing {}, invalid value {:?}",
+                key, value
+            ))
+        };
+
+        if !is_header_name(name) {
+            return Err(invalid(key, name));
+        }
+
+        let mut rest = value;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').map(|end| start + end);
+            match end {
+                Some(end) if PLACEHOLDERS.contains(&&rest[start + 1..end]) => {
+                    rest = &rest[end + 1..]
+                }
+                _ => return Err(invalid(&format!("{}_value", key), value)),
+            }
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        })
+    }
+
+    /// header name i.e. `X-Postkeeper-Allow`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// header value with placeholders replaced by given fields
+    pub fn render(&self, fields: &HeaderFields) -> String {
+        self.value
+            .replace("{list}", fields.list)
+            .replace("{entry}", &fields.entry)
+            .replace("{recipient}", fields.recipient)
+            .replace("{sender}", fields.sender)
+            .replace("{timestamp}", &fields.timestamp.to_string())
+    }
+}
+
+// Checks that value can be used as a header name, printable ASCII without
+// spaces and colons (RFC 5322 field name)
+fn is_header_name(value: &str) -> bool {
+    !value.is_empty()
+        && value.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn render_template() {
+        let template = HeaderTemplate::parse(
+            "allow_header",
+            "X-Postkeeper-Allow",
+            "list={list}; entry={entry}; rcpt={recipient}; from={sender}; \
+             at={timestamp}",
+        )
+        .unwrap();
+        let fields = HeaderFields {
+            list: "allow",
+            entry: "@example.org".to_owned(),
+            recipient: "bob@example.com",
+            sender: "kale@example.org",
+            timestamp: 1700000000,
+        };
+
+        assert_eq!(template.name(), "X-Postkeeper-Allow");
+        assert_eq!(
+            template.render(&fields),
+            "list=allow; entry=@example.org; rcpt=bob@example.com; \
+             from=kale@example.org; at=1700000000"
+        );
+    }
+
+    #[test]
+    fn invalid_template() {
+        assert_eq!(
+            HeaderTemplate::parse("allow_header", "X Allow", "Yes"),
+            Err(Error::config_err(
+                "Error parsing allow_header, invalid value \"X Allow\""
+            ))
+        );
+        assert_eq!(
+            HeaderTemplate::parse("allow_header", "X-Allow", "{rcpt}"),
+            Err(Error::config_err(
+                "Error parsing allow_header_value, invalid value \"{rcpt}\""
+            ))
+        );
+        assert_eq!(
+            HeaderTemplate::parse("allow_header", "X-Allow", "list={list"),
+            Err(Error::config_err(
+                "Error parsing allow_header_value, invalid value \"list={list\""
+            ))
+        );
+    }
+}
@@ -4,12 +4,16 @@
 #![warn(unused_variables)]
 #![warn(dead_code)]
 
+mod address;
+mod auth;
 mod config;
 mod consts;
 mod error;
+mod header;
 mod maps;
 mod milter;
 mod prelude;
+mod session;
 
 extern crate simple_logger;
 
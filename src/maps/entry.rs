@@ -0,0 +1,318 @@
+//! Postkeeper map entry implementation
+
+use super::network::Network;
+use crate::address::{
+    canonical_domain, canonicalize, domain_of, is_subdomain_of, SenderMatch,
+};
+use crate::consts::NULL_SENDER;
+use regex::{Regex, RegexBuilder};
+use std::fmt;
+
+/// A single sender value of a map line, a pattern optionally followed by
+/// `;` separated `option=value` pairs
+///
+/// EXAMPLE:
+///  `bob@example.com`     matches the sender `bob@example.com` only
+///  `<>`                  matches the null sender of bounces and DSNs
+///  `@example.com`        matches any sender at `example.com`
+///  `*.example.com`       matches any sender at a subdomain of `example.com`
+///  `news-*@example.com`  glob, `*` matches any characters, `?` a single one
+///  `/^news-\d+@example\.com$/` regular expression, case insensitive
+///  `192.0.2.0/24`        matches any sender connecting from the range
+///                        (see [`Network`])
+///  `host:*.example.net`  matches clients with a verified hostname in a
+///                        subdomain of `example.net`
+///  `helo:mx.example.net` matches clients that greet with `mx.example.net`
+///  `list:dev.lists.example.org` matches messages of the mailing list with
+///                        `List-Id` `dev.lists.example.org`
+///  `list:dev@lists.example.org` matches messages of the mailing list with
+///                        `List-Post` address `dev@lists.example.org`
+///  `bob@example.com;reply=rude` uses configured `[reply.rude]` SMTP reply
+///                        when the entry blocks a message
+///  `@news.example;match=header` matched against the `From:` header address
+///                        instead of the global `sender_match`
+///  `!boss@example.com`   exception, sender is not matched by less specific
+///                        entries of the same list i.e. `@example.com`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pattern: Pattern,
+    exception: bool,
+    reply: Option<String>,
+    sender_match: Option<SenderMatch>,
+}
+
+/// What a map entry matches against
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// full sender address, empty for the null sender `<>`
+    Address(String),
+    /// every sender of the domain
+    Domain(String),
+    /// every sender of any subdomain of the domain
+    SubDomain(String),
+    /// glob matched against the whole sender address
+    Glob(Expression),
+    /// regular expression matched against the sender address
+    Regex(Expression),
+    /// client IP address or range the message is received from
+    Network(Network),
+    /// glob matched against the verified client hostname (`host:`)
+    Host(Expression),
+    /// glob matched against the HELO name of the client (`helo:`)
+    Helo(Expression),
+    /// mailing list id or posting address of the message (`list:`)
+    List(String),
+}
+
+/// A glob or regular expression compiled when the map is loaded
+#[derive(Debug, Clone)]
+pub struct Expression {
+    source: String,
+    regex: Regex,
+}
+
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Entry {
+    /// parses a single map value, values are canonicalized before storing
+    /// returns None if value is a domain entry without a domain
+    pub fn parse(value: &str) -> Option<Self> {
+        let (exception, value) = match value.strip_prefix('!') {
+            Some(value) => (true, value),
+            None => (false, value),
+        };
+        let (pattern, options) = split_options(value)?;
+        let mut entry = Entry {
+            pattern: Pattern::parse(pattern)?,
+            exception,
+            reply: None,
+            sender_match: None,
+        };
+
+        for option in options.split(';').filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some(("reply", name)) if !name.is_empty() => {
+                    entry.reply = Some(name.to_owned())
+                }
+                Some(("match", value)) if SenderMatch::parse(value).is_some() =>
+                {
+                    entry.sender_match = SenderMatch::parse(value)
+                }
+                _ => log::warn!("Ignoring unknown option `{}`", option),
+            }
+        }
+
+        Some(entry)
+    }
+
+    /// plain `<>` entry, matches the null sender
+    pub fn null_sender() -> Self {
+        Entry {
+            pattern: Pattern::Address(String::new()),
+            exception: false,
+            reply: None,
+            sender_match: None,
+        }
+    }
+
+    /// checks if given canonical sender address is matched by this entry
+    /// client entries (network, host and helo) and list entries never match a
+    /// sender address
+    pub fn matches(&self, sender: &str) -> bool {
+        self.pattern.matches(sender)
+    }
+
+    /// what this entry matches against
+    pub fn pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    /// true for a negated (`!`) entry
+    pub fn is_exception(&self) -> bool {
+        self.exception
+    }
+
+    /// true if this entry is matched against the kind of sender `address`
+    /// (envelope or header), `default` applies without a `match` option
+    pub fn applies_to(
+        &self,
+        address: SenderMatch,
+        default: SenderMatch,
+    ) -> bool {
+        self.sender_match.unwrap_or(default).applies_to(address)
+    }
+
+    /// name of the configured SMTP reply for this entry if any
+    pub fn reply(&self) -> Option<&str> {
+        self.reply.as_deref()
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.exception {
+            write!(f, "!")?;
+        }
+        write!(f, "{}", self.pattern)
+    }
+}
+
+impl Pattern {
+    fn parse(value: &str) -> Option<Self> {
+        if value == NULL_SENDER {
+            Some(Pattern::Address(String::new()))
+        } else if let Some(regex) =
+            value.strip_prefix('/').and_then(|v| v.strip_suffix('/'))
+        {
+            Expression::regex(regex).map(Pattern::Regex)
+        } else if let Some(name) = value.strip_prefix("host:") {
+            Self::hostname(name).map(Pattern::Host)
+        } else if let Some(name) = value.strip_prefix("helo:") {
+            Self::hostname(name).map(Pattern::Helo)
+        } else if let Some(list) = value.strip_prefix("list:") {
+            Self::list(list).map(Pattern::List)
+        } else if let Some(domain) = value.strip_prefix("*.") {
+            Self::domain(domain).map(Pattern::SubDomain)
+        } else if let Some(domain) = value.strip_prefix('@') {
+            Self::domain(domain).map(Pattern::Domain)
+        } else if let Some(network) = Network::parse(value) {
+            Some(Pattern::Network(network))
+        } else if value.contains(['*', '?']) {
+            Expression::glob(&canonicalize(value)).map(Pattern::Glob)
+        } else {
+            Some(Pattern::Address(canonicalize(value)))
+        }
+    }
+
+    // hostname glob of a `host:` or `helo:` entry, None if name is empty
+    fn hostname(name: &str) -> Option<Expression> {
+        Some(name.trim_end_matches('.'))
+            .filter(|name| !name.is_empty())
+            .and_then(|name| Expression::glob(&canonical_domain(name)))
+    }
+
+    // canonical list id or posting address of a `list:` entry, None if the
+    // list is empty
+    fn list(list: &str) -> Option<String> {
+        Some(list.trim_end_matches('.'))
+            .filter(|list| !list.is_empty())
+            .map(|list| {
+                if list.contains('@') {
+                    canonicalize(list)
+                } else {
+                    canonical_domain(list)
+                }
+            })
+    }
+
+    // canonical domain of a domain entry, None if domain is empty
+    fn domain(domain: &str) -> Option<String> {
+        Some(domain)
+            .filter(|domain| !domain.is_empty())
+            .map(canonical_domain)
+    }
+
+    fn matches(&self, sender: &str) -> bool {
+        match self {
+            Pattern::Address(address) => address == sender,
+            Pattern::Domain(domain) => {
+                domain_of(sender).is_some_and(|d| d == domain)
+            }
+            Pattern::SubDomain(domain) => {
+                domain_of(sender).is_some_and(|d| is_subdomain_of(d, domain))
+            }
+            Pattern::Glob(expression) | Pattern::Regex(expression) => {
+                expression.is_match(sender)
+            }
+            // client and list values are matched by `SenderList`
+            Pattern::Network(_)
+            | Pattern::Host(_)
+            | Pattern::Helo(_)
+            | Pattern::List(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Address(address) if address.is_empty() => {
+                write!(f, "{}", NULL_SENDER)
+            }
+            Pattern::Address(address) => write!(f, "{}", address),
+            Pattern::Domain(domain) => write!(f, "@{}", domain),
+            Pattern::SubDomain(domain) => write!(f, "*.{}", domain),
+            Pattern::Glob(expression) => write!(f, "{}", expression.source),
+            Pattern::Regex(expression) => write!(f, "/{}/", expression.source),
+            Pattern::Network(network) => write!(f, "{}", network),
+            Pattern::Host(expression) => {
+                write!(f, "host:{}", expression.source)
+            }
+            Pattern::Helo(expression) => {
+                write!(f, "helo:{}", expression.source)
+            }
+            Pattern::List(list) => write!(f, "list:{}", list),
+        }
+    }
+}
+
+impl Expression {
+    /// true if the whole value is matched, case insensitive
+    pub fn is_match(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+
+    // compiles a case insensitive regular expression, logs invalid ones
+    // empty expression is invalid
+    fn regex(source: &str) -> Option<Self> {
+        if source.is_empty() {
+            return None;
+        }
+        let regex = RegexBuilder::new(source)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| log::warn!("Invalid regular expression, {}", e))
+            .ok()?;
+        Some(Self {
+            source: source.to_owned(),
+            regex,
+        })
+    }
+
+    // compiles a glob into an anchored regular expression
+    fn glob(source: &str) -> Option<Self> {
+        let mut regex = String::from("^");
+        for c in source.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        regex.push('$');
+        let Expression { regex, .. } = Self::regex(&regex)?;
+        Some(Self {
+            source: source.to_owned(),
+            regex,
+        })
+    }
+}
+
+/// splits a map value into its pattern and `;` separated options
+/// `;` inside a `/regex/` is part of the pattern, None if anything other than
+/// options follows the closing `/`
+fn split_options(value: &str) -> Option<(&str, &str)> {
+    if value.len() > 1 && value.starts_with('/') {
+        let end = value.rfind('/')?;
+        let (pattern, options) = value.split_at(end + 1);
+        if end == 0 || !(options.is_empty() || options.starts_with(';')) {
+            return None;
+        }
+        return Some((pattern, options));
+    }
+    Some(value.split_once(';').unwrap_or((value, "")))
+}
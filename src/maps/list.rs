@@ -0,0 +1,317 @@
+//! Postkeeper sender list of a single map key
+
+use super::entry::{Entry, Pattern};
+use crate::address::{domain_of, SenderMatch};
+use std::collections::HashMap;
+use std::iter::repeat;
+use std::net::IpAddr;
+
+/// Sender entries of a map key indexed for lookup
+///
+/// address, domain and subdomain entries are found with hash lookups, glob
+/// and regex entries are only evaluated (in map order) if none of those
+/// match, so lookup time of plain entries does not depend on the number of
+/// patterns. network entries are checked against the client address, longest
+/// prefix first, `host:` and `helo:` entries against the client hostname and
+/// HELO name, `list:` entries against the mailing list of the message.
+///
+/// the most specific matching entry wins, an exception (`!`) entry excludes
+/// the sender from less specific entries of the list
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SenderList {
+    addresses: HashMap<String, Entry>,
+    lists: HashMap<String, Entry>,
+    domains: HashMap<String, Entry>,
+    subdomains: HashMap<String, Entry>,
+    /// sorted by prefix length, longest first
+    networks: Vec<Entry>,
+    /// host and helo exceptions are checked before other entries
+    hosts: Vec<Entry>,
+    helos: Vec<Entry>,
+    /// pattern exceptions are checked before other patterns
+    pattern_exceptions: Vec<Entry>,
+    patterns: Vec<Entry>,
+}
+
+/// Canonical values of a message origin an entry can match
+#[derive(Debug, Default)]
+pub struct Candidates {
+    /// sender address variants, see [`crate::address::AddressOptions`]
+    pub senders: Vec<String>,
+    /// `From:` header address variants
+    pub header_senders: Vec<String>,
+    /// sender address entries without `match` option apply to
+    pub sender_match: SenderMatch,
+    pub client_ip: Option<IpAddr>,
+    /// verified hostname of the client
+    pub client_name: Option<String>,
+    pub helo: Option<String>,
+    /// `List-Id` and `List-Post` address of the message
+    pub lists: Vec<String>,
+}
+
+impl Candidates {
+    // sender variants with the kind of address they come from, envelope
+    // sender first
+    fn senders(&self) -> impl Iterator<Item = (&str, SenderMatch)> {
+        let envelope = self.senders.iter().zip(repeat(SenderMatch::Envelope));
+        let header = self.header_senders.iter();
+        let header = header.zip(repeat(SenderMatch::Header));
+        envelope.chain(header).map(|(s, kind)| (s.as_str(), kind))
+    }
+}
+
+impl SenderList {
+    /// returns the most specific entry that matches the candidates: sender
+    /// address, mailing list, client network, client hostname, HELO name,
+    /// sender domain, closest parent domain, then first matching pattern.
+    /// Returned entry can be an exception
+    pub fn find(&self, candidates: &Candidates) -> Option<&Entry> {
+        let client_name = candidates.client_name.as_deref();
+        let helo = candidates.helo.as_deref();
+        let default = candidates.sender_match;
+        let applies = |entry: &Entry, kind| entry.applies_to(kind, default);
+        candidates
+            .senders()
+            .find_map(|(sender, kind)| {
+                self.addresses.get(sender).filter(|e| applies(e, kind))
+            })
+            .or_else(|| {
+                candidates.lists.iter().find_map(|list| self.lists.get(list))
+            })
+            .or_else(|| {
+                candidates.client_ip.and_then(|ip| self.find_network(ip))
+            })
+            .or_else(|| client_name.and_then(|n| find_name(&self.hosts, n)))
+            .or_else(|| helo.and_then(|name| find_name(&self.helos, name)))
+            .or_else(|| {
+                candidates.senders().find_map(|(sender, kind)| {
+                    self.find_domain(sender, |e| applies(e, kind))
+                })
+            })
+            .or_else(|| {
+                self.pattern_exceptions
+                    .iter()
+                    .chain(&self.patterns)
+                    .find(|entry| {
+                        candidates.senders().any(|(sender, kind)| {
+                            applies(entry, kind) && entry.matches(sender)
+                        })
+                    })
+            })
+    }
+
+    /// plain sender address entries of the list, the contacts a `From:`
+    /// display name can impersonate
+    pub fn contacts(&self) -> impl Iterator<Item = (&str, &Entry)> {
+        self.addresses
+            .iter()
+            .filter(|(address, entry)| {
+                !address.is_empty() && !entry.is_exception()
+            })
+            .map(|(address, entry)| (address.as_str(), entry))
+    }
+
+    /// exception entries that can never fire, no less specific entry of the
+    /// list covers what they match
+    pub fn unreachable_exceptions(&self) -> Vec<&Entry> {
+        let has_patterns = !self.patterns.is_empty();
+        let has_domains = has_positive(self.domains.values())
+            || has_positive(self.subdomains.values());
+        let has_helos = has_positive(&self.helos);
+        let has_hosts = has_positive(&self.hosts);
+        let has_networks = has_positive(&self.networks);
+        let has_lists = has_positive(self.lists.values());
+        let mut unreachable: Vec<&Entry> = Vec::new();
+
+        // address exceptions are covered by a list or client entry, a domain
+        // or a pattern entry
+        for (address, entry) in &self.addresses {
+            if !entry.is_exception() {
+                continue;
+            }
+            let covered = has_lists
+                || has_networks
+                || has_hosts
+                || has_helos
+                || self
+                .find_domain(address, |_| true)
+                .or_else(|| self.patterns.iter().find(|e| e.matches(address)))
+                .is_some_and(|e| !e.is_exception());
+            if !covered {
+                unreachable.push(entry);
+            }
+        }
+
+        // `@d` and `*.d` exceptions are covered by subdomain entries of the
+        // parents of `d` or by any pattern
+        for (domain, entry) in self.domains.iter().chain(&self.subdomains) {
+            if !entry.is_exception() || has_patterns {
+                continue;
+            }
+            let mut parent = domain.as_str();
+            let mut covered = false;
+            while let Some((_, rest)) = parent.split_once('.') {
+                let entry = self.subdomains.get(rest);
+                if entry.is_some_and(|e| !e.is_exception()) {
+                    covered = true;
+                    break;
+                }
+                parent = rest;
+            }
+            if !covered {
+                unreachable.push(entry);
+            }
+        }
+
+        if !has_patterns {
+            unreachable.extend(&self.pattern_exceptions);
+        }
+
+        // host and helo exceptions are covered by any less specific entry
+        let has_sender_entries = has_patterns || has_domains;
+        if !(has_helos || has_sender_entries) {
+            unreachable.extend(self.helos.iter().filter(|e| e.is_exception()));
+        }
+        if !(has_hosts || has_helos || has_sender_entries) {
+            unreachable.extend(self.hosts.iter().filter(|e| e.is_exception()));
+        }
+
+        // network exceptions are covered by a shorter network prefix or any
+        // less specific entry
+        let has_sender_entries = has_sender_entries || has_hosts || has_helos;
+        for entry in &self.networks {
+            let network = match entry.pattern() {
+                Pattern::Network(network) if entry.is_exception() => network,
+                _ => continue,
+            };
+            let covered = has_sender_entries
+                || self.networks.iter().any(|e| match e.pattern() {
+                    Pattern::Network(other) => {
+                        !e.is_exception()
+                            && other.prefix() < network.prefix()
+                            && other.covers(network)
+                    }
+                    _ => false,
+                });
+            if !covered {
+                unreachable.push(entry);
+            }
+        }
+
+        // list exceptions are covered by any less specific entry
+        if !(has_networks || has_sender_entries) {
+            let lists = self.lists.values();
+            unreachable.extend(lists.filter(|e| e.is_exception()));
+        }
+        unreachable
+    }
+
+    fn find_network(&self, ip: IpAddr) -> Option<&Entry> {
+        self.networks.iter().find(|entry| match entry.pattern() {
+            Pattern::Network(network) => network.contains(ip),
+            _ => false,
+        })
+    }
+
+    // closest domain or parent domain entry of the sender that `applies`
+    fn find_domain(
+        &self,
+        sender: &str,
+        applies: impl Fn(&Entry) -> bool,
+    ) -> Option<&Entry> {
+        let domain = domain_of(sender)?;
+        if let Some(entry) = self.domains.get(domain).filter(|e| applies(e)) {
+            return Some(entry);
+        }
+        // walk parent domains, closest first
+        let mut parent = domain;
+        while let Some((_, rest)) = parent.split_once('.') {
+            let entry = self.subdomains.get(rest);
+            if let Some(entry) = entry.filter(|e| applies(e)) {
+                return Some(entry);
+            }
+            parent = rest;
+        }
+        None
+    }
+}
+
+// first `host:` or `helo:` entry that matches the name
+fn find_name<'a>(entries: &'a [Entry], name: &str) -> Option<&'a Entry> {
+    entries.iter().find(|entry| match entry.pattern() {
+        Pattern::Host(expression) | Pattern::Helo(expression) => {
+            expression.is_match(name)
+        }
+        _ => false,
+    })
+}
+
+// true if any of the entries is not an exception
+fn has_positive<'a>(entries: impl IntoIterator<Item = &'a Entry>) -> bool {
+    entries.into_iter().any(|entry| !entry.is_exception())
+}
+
+impl FromIterator<Entry> for SenderList {
+    /// first entry wins if the same address or domain is listed twice, an
+    /// exception wins over the same value listed as a plain entry
+    fn from_iter<I: IntoIterator<Item = Entry>>(entries: I) -> Self {
+        let mut list = SenderList::default();
+        for entry in entries {
+            let index = match entry.pattern() {
+                Pattern::Address(address) => (&mut list.addresses, address),
+                Pattern::List(id) => (&mut list.lists, id),
+                Pattern::Domain(domain) => (&mut list.domains, domain),
+                Pattern::SubDomain(domain) => (&mut list.subdomains, domain),
+                Pattern::Network(_) => {
+                    list.networks.push(entry);
+                    continue;
+                }
+                Pattern::Host(_) => {
+                    list.hosts.push(entry);
+                    continue;
+                }
+                Pattern::Helo(_) => {
+                    list.helos.push(entry);
+                    continue;
+                }
+                Pattern::Glob(_) | Pattern::Regex(_) => {
+                    if entry.is_exception() {
+                        list.pattern_exceptions.push(entry);
+                    } else {
+                        list.patterns.push(entry);
+                    }
+                    continue;
+                }
+            };
+            let (map, key) = index;
+            match map.get(key) {
+                None => {
+                    map.insert(key.clone(), entry);
+                }
+                Some(first) if first.is_exception() != entry.is_exception() =>
+                {
+                    log::warn!(
+                        "`{}` is listed both as entry and exception, \
+                         exception wins",
+                        entry.pattern()
+                    );
+                    if entry.is_exception() {
+                        map.insert(key.clone(), entry);
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        // longest prefix first, an exception before an entry of same prefix
+        list.networks.sort_by_key(|entry| match entry.pattern() {
+            Pattern::Network(network) => {
+                (std::cmp::Reverse(network.prefix()), !entry.is_exception())
+            }
+            _ => (std::cmp::Reverse(0), true),
+        });
+        list.hosts.sort_by_key(|entry| !entry.is_exception());
+        list.helos.sort_by_key(|entry| !entry.is_exception());
+        list
+    }
+}
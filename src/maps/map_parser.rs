@@ -1,5 +1,8 @@
 //! Postkeeper milter map parser implementation
 
+use super::entry::Entry;
+use super::list::SenderList;
+use crate::address::canonicalize;
 use crate::prelude::*;
 use std::{
     collections::HashMap,
@@ -14,8 +17,11 @@ use std::{
 /// character[s]. line text starting with `#` treated as comment and ignored.
 /// either at the beginning of the line and also in multiline context
 /// comments are not permitted on lines with data
+/// a key listed on more than one line gets the values of every line
 ///
-/// values are treated case insensitive
+/// keys and values are stored in canonical form (see [`canonicalize`]), a
+/// value can be a full address, a domain entry or a glob/regex pattern
+/// (see [`Entry`]), patterns are compiled here when the map is loaded
 /// EXAMPLE:
 ///  teresa@example.com gay@example.com candice@example.net
 /// cornelius@example.net jarret@example.org zachariah@example.org
@@ -24,9 +30,13 @@ use std::{
 ///  alayna@example.com claude@example.net stephan@example.net
 ///     jordan@example.net
 ///     juston@example.com
+/// kieran@example.com @spammy.example *.spammy.example
+/// kieran@example.com news-*@bulk.example /^news-\d+@bulk\.example$/
+/// kieran@example.com @bigcorp.example !boss@bigcorp.example
 #[derive(Debug)]
 pub struct MapParser {
-    map: HashMap<String, Vec<String>>,
+    /// values of every line of a key, in map order
+    map: HashMap<String, Vec<Entry>>,
 }
 
 impl MapParser {
@@ -74,9 +84,24 @@ impl MapParser {
         Ok(parser)
     }
 
-    /// consumes the parser and returns the inner parsed HashMap
-    pub fn into_map(self) -> HashMap<String, Vec<String>> {
+    /// consumes the parser and returns the parsed sender lists by key
+    /// warns about exceptions that can never match
+    pub fn into_map(self) -> HashMap<String, SenderList> {
         self.map
+            .into_iter()
+            .map(|(key, entries)| {
+                let list: SenderList = entries.into_iter().collect();
+                for entry in list.unreachable_exceptions() {
+                    log::warn!(
+                        "Exception `{}` for {} can never match, no less \
+                         specific entry covers it",
+                        entry,
+                        key
+                    );
+                }
+                (key, list)
+            })
+            .collect()
     }
 
     // process a logical single line of map
@@ -91,11 +116,26 @@ impl MapParser {
         }
 
         if let Some((head, tail)) = list.split_first() {
-            // lowercase recipient email before inserting
-            let key = (*head).to_lowercase();
-            // values are inserted as is (lowercased on check-time)
-            let value = tail.iter().map(|v| (*v).to_owned()).collect();
-            self.map.insert(key, value);
+            // canonicalize recipient email before inserting
+            let key = canonicalize(head);
+            let value: Vec<Entry> = tail
+                .iter()
+                .filter_map(|v| {
+                    let entry = Entry::parse(v);
+                    if entry.is_none() {
+                        log::warn!(
+                            "Skip parsing invalid value `{}` for {}",
+                            v,
+                            key
+                        );
+                    }
+                    entry
+                })
+                .collect();
+            if self.map.contains_key(&key) {
+                log::info!("Key {} is listed again, values are merged", key);
+            }
+            self.map.entry(key).or_default().extend(value);
         }
     }
 }
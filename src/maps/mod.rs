@@ -1,19 +1,32 @@
 //! Postkeeper global map management
 
+mod entry;
+mod list;
 mod map_parser;
+mod network;
+use crate::address::{
+    canonical_domain, canonicalize, display_name_matches, domain_of,
+    AddressOptions,
+};
 use crate::config::global_conf;
 use crate::prelude::*;
 use lazy_static::lazy_static;
+pub use entry::Entry;
+use list::{Candidates, SenderList};
 use map_parser::{last_modified, MapParser};
 use std::{
     collections::HashMap,
+    net::IpAddr,
     ops::Deref,
     path::Path,
     sync::RwLock,
     time::{Duration, SystemTime},
 };
 
-type PostKeepMap = RwLock<HashMap<String, Vec<String>>>;
+/// reserved map key, its entries apply to every recipient
+pub const GLOBAL_KEY: &str = "*";
+
+type PostKeepMap = RwLock<HashMap<String, SenderList>>;
 type LastUpdatedTime = RwLock<SystemTime>;
 
 // global objects are required due to `milter` crate nature of using callbacks.
@@ -124,44 +137,247 @@ fn should_update(
     }
 }
 
-/// query the global BLOCK_MAP to match if
-/// given recipient has blocked the sender
-pub fn is_blocked(recipient: &str, sender: &str) -> bool {
-    let recipient = recipient.to_lowercase();
+/// result of matching a sender against the maps of a recipient
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// sender is in the block list of the recipient
+    Blocked(Match),
+    /// sender is in the allow list of the recipient
+    Allowed(Match),
+    /// neither list of the recipient has the sender
+    NoMatch,
+}
+
+/// map key and entry that decided a verdict
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    /// map key the entry was found under
+    pub key: String,
+    /// entry that matched the sender
+    pub entry: Entry,
+}
+
+/// what is known about where a message comes from, matched against map
+/// entries of a recipient
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Origin<'a> {
+    /// envelope sender address
+    pub sender: &'a str,
+    /// IP address of the connected client
+    pub client_ip: Option<IpAddr>,
+    /// address of the `From:` header, not known before the message is sent
+    pub header_from: Option<&'a str>,
+    /// verified hostname of the connected client
+    pub client_name: Option<&'a str>,
+    /// name the client sent with `HELO`/`EHLO`
+    pub helo: Option<&'a str>,
+    /// `List-Id` of the message, not known before the message is sent
+    pub list_id: Option<&'a str>,
+    /// `List-Post` address of the message, not known before the message is
+    /// sent
+    pub list_post: Option<&'a str>,
+    /// allow list matches are ignored, sender failed required authentication
+    pub skip_allow: bool,
+    /// allow list matches of the decoded sender are ignored, the domain of
+    /// the decoded address failed required authentication
+    pub skip_decoded_allow: bool,
+}
+
+/// query the global BLOCK_MAP and ALLOW_MAP for given recipient and origin
+/// recipient and sender addresses are compared in canonical form
+/// (see [`canonicalize`])
+///
+/// recipient is looked up with its full address first, then without its
+/// detail part (if `recipient_delimiter` is set), then with its domain key
+/// (`@example.com`) and last with the global key (`*`), the first key that
+/// has a match in either list decides the verdict, block list is checked
+/// before allow list. This allows a mailbox entry to add to or override the
+/// domain entry and both to override the global entry
+pub fn lookup(
+    recipient: &str,
+    origin: &Origin,
+    options: &AddressOptions,
+) -> Verdict {
+    let recipient = canonicalize(recipient);
+    let candidates = origin_candidates(origin, options);
+    // allow entries only see the sender as sent
+    let allow_candidates = origin
+        .skip_decoded_allow
+        .then(|| origin_candidates(origin, &options.without_decoders()));
+    let allow_candidates = allow_candidates.as_ref().unwrap_or(&candidates);
 
     log::trace!(
-        "trying to find block match for recpt: {}, sender: {}",
+        "trying to find block/allow match for recpt: {}, origin: {:?}",
         recipient,
-        sender
+        origin
     );
 
-    let map = BLOCK_MAP.read().unwrap();
+    let block_map = BLOCK_MAP.read().unwrap();
+    let allow_map = ALLOW_MAP.read().unwrap();
 
-    if let Some(senders) = map.get(&recipient) {
-        senders.iter().any(|s| s.eq_ignore_ascii_case(sender))
-    } else {
-        false
+    for key in recipient_keys(&recipient, options) {
+        if let Some(entry) = find_match(&block_map, &key, &candidates) {
+            log::debug!("Block match `{}` found for key `{}`", entry, key);
+            let entry = entry.clone();
+            return Verdict::Blocked(Match { key, entry });
+        }
+
+        if let Some(entry) = find_match(&allow_map, &key, allow_candidates) {
+            if origin.skip_allow {
+                log::info!(
+                    "Ignoring allow match `{}` for key `{}`, \
+                     sender is not authenticated",
+                    entry,
+                    key
+                );
+                continue;
+            }
+            log::debug!("Allow match `{}` found for key `{}`", entry, key);
+            let entry = entry.clone();
+            return Verdict::Allowed(Match { key, entry });
+        }
+    }
+
+    Verdict::NoMatch
+}
+
+// canonical values of the origin that map entries are matched against
+fn origin_candidates(origin: &Origin, options: &AddressOptions) -> Candidates {
+    Candidates {
+        senders: options.sender_variants(&canonicalize(origin.sender)),
+        header_senders: origin
+            .header_from
+            .map(|from| options.sender_variants(&canonicalize(from)))
+            .unwrap_or_default(),
+        sender_match: options.sender_match(),
+        client_ip: origin.client_ip,
+        client_name: origin.client_name.and_then(canonical_host),
+        helo: origin.helo.and_then(canonical_host),
+        lists: origin
+            .list_id
+            .and_then(canonical_host)
+            .into_iter()
+            .chain(origin.list_post.map(canonicalize))
+            .collect(),
     }
 }
 
-/// query the global ALLOW_MAP to match if
-/// given recipient has the sender in allow-list
-pub fn is_allowed(recipient: &str, sender: &str) -> bool {
-    let recipient = recipient.to_lowercase();
+/// query the global BLOCK_MAP with a local sender as key for given recipient
+/// used for mail of authenticated local users, the block entries of the sender
+/// (its address and the address without detail part) are matched against the
+/// recipient address. Domain and global keys do not apply, neither do client
+/// entries. Returns the matched entry if the sender has blocked the recipient
+pub fn lookup_outbound(
+    sender: &str,
+    recipient: &str,
+    options: &AddressOptions,
+) -> Option<Match> {
+    let sender = canonicalize(sender);
+    let recipients = options.sender_variants(&canonicalize(recipient));
+    // recipient is matched by every address entry, whatever `match` it has
+    let candidates = Candidates {
+        header_senders: recipients.clone(),
+        senders: recipients,
+        ..Default::default()
+    };
 
-    log::info!(
-        "trying to find allow match for recpt: {}, sender: {}",
-        recipient,
-        sender
+    let block_map = BLOCK_MAP.read().unwrap();
+    options.recipient_variants(&sender).into_iter().find_map(|key| {
+        let entry = find_match(&block_map, &key, &candidates)?.clone();
+        log::debug!("Outbound block match `{}` found for key `{}`", entry, key);
+        Some(Match { key, entry })
+    })
+}
+
+/// query the global ALLOW_MAP for a contact of the recipient the `From:`
+/// header impersonates, its display name matches the contact (see
+/// [`display_name_matches`]) but its address is none of the contacts.
+/// Address entries of every key of the recipient are its contacts.
+/// Returns the key and entry of the impersonated contact
+pub fn impersonated_contact(
+    recipient: &str,
+    display_name: &str,
+    header_from: &str,
+    options: &AddressOptions,
+) -> Option<Match> {
+    let recipient = canonicalize(recipient);
+    let senders = options.sender_variants(&canonicalize(header_from));
+
+    let allow_map = ALLOW_MAP.read().unwrap();
+    let lists: Vec<(String, &SenderList)> = recipient_keys(&recipient, options)
+        .into_iter()
+        .filter_map(|key| allow_map.get(&key).map(|list| (key, list)))
+        .collect();
+    let contacts = || {
+        lists.iter().flat_map(|(key, list)| {
+            list.contacts().map(move |(address, entry)| (key, address, entry))
+        })
+    };
+
+    if contacts().any(|(_, address, _)| senders.iter().any(|s| s == address)) {
+        return None;
+    }
+    let (key, address, entry) = contacts()
+        .find(|(_, address, _)| display_name_matches(display_name, address))?;
+    log::debug!(
+        "Display name `{}` of `{}` matches `{}` of key `{}`",
+        display_name,
+        header_from,
+        address,
+        key
     );
+    Some(Match {
+        key: key.clone(),
+        entry: entry.clone(),
+    })
+}
 
-    let map = ALLOW_MAP.read().unwrap();
+/// map keys for given canonical recipient, in order of precedence
+fn recipient_keys(recipient: &str, options: &AddressOptions) -> Vec<String> {
+    let mut keys = options.recipient_variants(recipient);
 
-    if let Some(senders) = map.get(&recipient) {
-        senders.iter().any(|s| s.eq_ignore_ascii_case(sender))
-    } else {
-        false
+    if let Some(domain) = domain_of(recipient) {
+        keys.push(format!("@{}", domain));
+    }
+
+    keys.push(GLOBAL_KEY.to_owned());
+    keys
+}
+
+/// canonical form of a client hostname or HELO name, the trailing dot of a
+/// fully qualified name is dropped, None if nothing is left
+fn canonical_host(name: &str) -> Option<String> {
+    Some(name.trim().trim_end_matches('.'))
+        .filter(|name| !name.is_empty())
+        .map(canonical_domain)
+}
+
+/// returns the entry of the map key that matches the candidates, see
+/// [`SenderList::find`], None if the most specific match is an exception
+fn find_match<'a>(
+    map: &'a HashMap<String, SenderList>,
+    key: &str,
+    candidates: &Candidates,
+) -> Option<&'a Entry> {
+    let entry = map.get(key)?.find(candidates)?;
+    if entry.is_exception() {
+        log::debug!("Exception `{}` found for key `{}`", entry, key);
+        return None;
     }
+    Some(entry)
+}
+
+/// loads the test fixture maps into global maps, only the first time this
+/// function is called
+#[cfg(test)]
+pub fn load_test_maps() {
+    use std::sync::Once;
+
+    static PREP_TEST: Once = Once::new();
+    PREP_TEST.call_once(|| {
+        assert_eq!(load_allow_map("tests/test_allow.map"), Ok(()));
+        assert_eq!(load_block_map("tests/test_block.map"), Ok(()));
+    });
 }
 
 /// return a copy of global SystemTime
@@ -181,22 +397,68 @@ fn block_last_updated() -> SystemTime {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::address::{Decoders, SenderMatch};
     use pretty_assertions::assert_eq;
     use std::fs;
     use std::ops::{Add, Sub};
-    use std::sync::Once;
 
-    static PREP_TEST: Once = Once::new();
-    /// Load the maps only the first time this method is called.
-    fn load_maps() {
-        PREP_TEST.call_once(|| {
-            assert_eq!(load_allow_map("tests/test_allow.map"), Ok(()));
-            assert_eq!(load_block_map("tests/test_block.map"), Ok(()));
-        });
+    fn lookup_sender(
+        recipient: &str,
+        sender: &str,
+        options: &AddressOptions,
+    ) -> Verdict {
+        let origin = Origin {
+            sender,
+            ..Default::default()
+        };
+        lookup(recipient, &origin, options)
+    }
+
+    fn address_options(
+        recipient_delimiter: Option<&str>,
+        strip_sender_detail: bool,
+        sender_match: SenderMatch,
+    ) -> AddressOptions {
+        AddressOptions::new(
+            recipient_delimiter.map(String::from),
+            strip_sender_detail,
+            sender_match,
+            Decoders::default(),
+        )
+    }
+
+    fn is_blocked(recipient: &str, sender: &str) -> bool {
+        let options = AddressOptions::default();
+        let verdict = lookup_sender(recipient, sender, &options);
+        matches!(verdict, Verdict::Blocked(_))
+    }
+
+    fn is_allowed(recipient: &str, sender: &str) -> bool {
+        let options = AddressOptions::default();
+        let verdict = lookup_sender(recipient, sender, &options);
+        matches!(verdict, Verdict::Allowed(_))
+    }
+
+    fn lookup_origin(recipient: &str, origin: &Origin) -> Verdict {
+        lookup(recipient, origin, &AddressOptions::default())
+    }
+
+    fn origin_blocked(recipient: &str, origin: &Origin) -> bool {
+        matches!(lookup_origin(recipient, origin), Verdict::Blocked(_))
+    }
+
+    fn origin_allowed(recipient: &str, origin: &Origin) -> bool {
+        matches!(lookup_origin(recipient, origin), Verdict::Allowed(_))
+    }
+
+    // map entry value in the form it is printed after parsing
+    fn parse_entry(value: &str) -> Option<String> {
+        Entry::parse(value).map(|e| e.to_string())
     }
+
     #[test]
     fn test_allow_map() {
-        load_maps();
+        load_test_maps();
         // first map of the file
         assert!(is_allowed("teresa@example.com", "taurean@example.org"));
         assert!(is_allowed("haskell@example.com", "yvette@example.net"));
@@ -216,7 +478,7 @@ mod tests {
 
     #[test]
     fn test_block_map() {
-        load_maps();
+        load_test_maps();
         // first map first and last match
         assert!(is_blocked("reanna@example.com", "kale@example.org"));
         assert!(is_blocked("reanna@example.com", "maximillia@example.net"));
@@ -226,6 +488,654 @@ mod tests {
         assert!(is_blocked("bertrand@example.org", "griffin@example.net"));
     }
 
+    #[test]
+    fn test_duplicate_keys() {
+        load_test_maps();
+        // values of a key listed twice are merged
+        assert!(is_blocked("kieran@example.com", "nina@example.org"));
+        assert!(is_blocked("kieran@example.com", "merged@example.org"));
+        // exception of the second line applies to entries of the first
+        assert!(is_blocked("kieran@example.com", "news@mx.bulk.example"));
+        assert!(!is_blocked("kieran@example.com", "promo@mx.bulk.example"));
+    }
+
+    #[test]
+    fn test_domain_entries() {
+        load_test_maps();
+        // `@domain` matches every sender of the domain
+        assert!(is_blocked("kieran@example.com", "nina@example.org"));
+        assert!(is_blocked("kieran@example.com", "anyone@spammy.example"));
+        assert!(is_blocked("kieran@example.com", "Anyone@SPAMMY.example"));
+        assert!(is_allowed("kieran@example.com", "jane@partner.example"));
+
+        // `*.domain` matches senders of subdomains only
+        assert!(is_blocked("kieran@example.com", "news@mx.bulk.example"));
+        assert!(is_blocked("kieran@example.com", "news@a.b.bulk.example"));
+        assert!(is_allowed("kieran@example.com", "jane@mail.trusted.example"));
+        assert!(!is_blocked("kieran@example.com", "news@bulk.example"));
+        assert!(!is_allowed("kieran@example.com", "jane@trusted.example"));
+
+        // `@domain` does not match subdomains or lookalike domains
+        assert!(!is_blocked("kieran@example.com", "anyone@sub.spammy.example"));
+        assert!(!is_blocked("kieran@example.com", "anyone@notspammy.example"));
+        assert!(!is_blocked("kieran@example.com", "news@notbulk.example"));
+        assert!(!is_allowed("kieran@example.com", "partner.example"));
+
+        // domain entries only apply to their own recipient
+        assert!(!is_blocked("reanna@example.com", "anyone@spammy.example"));
+    }
+
+    #[test]
+    fn test_domain_recipient_keys() {
+        load_test_maps();
+        // domain key applies to every mailbox of the domain
+        assert!(is_blocked("anyone@corp.example", "pest@example.org"));
+        assert!(is_blocked("Someone@Corp.example", "news@junk.example"));
+        assert!(is_allowed("anyone@corp.example", "friend@example.org"));
+        assert_eq!(
+            lookup_sender(
+                "anyone@corp.example",
+                "other@example.org",
+                &AddressOptions::default()
+            ),
+            Verdict::NoMatch
+        );
+
+        // domain key does not apply to other domains or subdomains
+        assert!(!is_blocked("anyone@sub.corp.example", "pest@example.org"));
+        assert!(!is_blocked("anyone@example.com", "pest@example.org"));
+
+        // mailbox allow entry overrides domain block entry
+        assert!(is_allowed("ceo@corp.example", "pest@example.org"));
+        // mailbox block entry overrides domain allow entry
+        assert!(is_blocked("ceo@corp.example", "friend@example.org"));
+        // mailbox entries add to the domain entries
+        assert!(is_blocked("ceo@corp.example", "rival@example.net"));
+        assert!(is_blocked("ceo@corp.example", "news@junk.example"));
+    }
+
+    #[test]
+    fn test_global_key() {
+        load_test_maps();
+        // global entries apply to every recipient
+        assert!(is_blocked("teresa@example.com", "abuser@example.org"));
+        assert!(is_blocked("nobody@example.net", "x@abusive.example"));
+        assert!(is_allowed("nobody@example.net", "status@monitoring.example"));
+
+        // recipient address key overrides the global key
+        assert!(is_allowed("ceo@corp.example", "abuser@example.org"));
+        // recipient domain key overrides the global key
+        assert!(is_allowed("anyone@corp.example", "x@abusive.example"));
+        // global entries still apply when more specific keys have no match
+        assert!(is_blocked("anyone@corp.example", "abuser@example.org"));
+    }
+
+    #[test]
+    fn test_smtputf8_addresses() {
+        load_test_maps();
+        // unicode local parts compare case insensitive
+        assert!(is_blocked(
+            "ärger@example.com",
+            "jörg@xn--bcher-kva.example"
+        ));
+        assert!(is_blocked("ÄRGER@EXAMPLE.com", "JÖRG@Bücher.example"));
+        assert!(!is_blocked("ärger@example.com", "jorg@bücher.example"));
+
+        // unicode domain entry matches its punycode form and vice versa
+        assert!(is_blocked("ärger@example.com", "x@MÜNCHEN.example"));
+        assert!(is_blocked("ärger@example.com", "x@xn--mnchen-3ya.example"));
+        assert!(is_blocked("ärger@example.com", "x@mail.münchen.example"));
+
+        // unicode recipient domain key in map, punycode recipient
+        assert!(is_blocked(
+            "anyone@xn--fsqu00a.xn--0zwm56d",
+            "用户@例子.测试"
+        ));
+        assert!(is_blocked(
+            "Anyone@例子.测试",
+            "用户@xn--fsqu00a.xn--0zwm56d"
+        ));
+    }
+
+    #[test]
+    fn test_pattern_entries() {
+        load_test_maps();
+        let recipient = "patterns@example.com";
+        // glob entries
+        assert!(is_blocked(recipient, "news-123@bulk.example"));
+        assert!(is_blocked(recipient, "NEWS-456@Bulk.Example"));
+        assert!(is_blocked(recipient, "ab@short.example"));
+        assert!(!is_blocked(recipient, "abc@short.example"));
+        assert!(!is_blocked(recipient, "news-123@bulk.example.org"));
+
+        // regex entries are case insensitive
+        assert!(is_blocked(recipient, "promo-42@deals.example"));
+        assert!(is_blocked(recipient, "Promo-42@DEALS.example"));
+        assert!(!is_blocked(recipient, "promo-x@deals.example"));
+        assert!(!is_blocked(recipient, "xpromo-42@deals.example"));
+        assert!(is_blocked(recipient, "a;b@semi.example"));
+
+        // exact entry is found before a pattern listed earlier
+        let options = AddressOptions::default();
+        match lookup_sender(recipient, "news-vip@bulk.example", &options) {
+            Verdict::Blocked(matched) => {
+                assert_eq!(matched.entry.to_string(), "news-vip@bulk.example");
+                assert_eq!(matched.entry.reply(), Some("policy"));
+            }
+            verdict => panic!("unexpected verdict {:?}", verdict),
+        }
+    }
+
+    #[test]
+    fn test_parse_pattern_entries() {
+        let entry = Entry::parse("/^a;b@semi\\.example$/;reply=policy");
+        let entry = entry.expect("valid regex entry");
+        assert_eq!(entry.to_string(), "/^a;b@semi\\.example$/");
+        assert_eq!(entry.reply(), Some("policy"));
+
+        let entry = Entry::parse("News-*@Bulk.Example").expect("valid glob");
+        assert_eq!(entry.to_string(), "news-*@bulk.example");
+
+        // invalid or empty expressions are skipped
+        assert_eq!(Entry::parse("/[a-/"), None);
+        assert_eq!(Entry::parse("//"), None);
+        assert_eq!(Entry::parse("/abc"), None);
+        assert_eq!(Entry::parse("/abc/x"), None);
+    }
+
+    #[test]
+    fn test_exception_entries() {
+        load_test_maps();
+        let recipient = "exceptions@example.com";
+        // address exception of a domain entry
+        assert!(is_blocked(recipient, "anyone@bigcorp.example"));
+        assert!(!is_blocked(recipient, "boss@bigcorp.example"));
+        assert!(!is_blocked(recipient, "Boss@BigCorp.example"));
+
+        // domain and subdomain exceptions of a subdomain entry
+        assert!(is_blocked(recipient, "news@a.bulk.example"));
+        assert!(!is_blocked(recipient, "news@mx.bulk.example"));
+        assert!(is_blocked(recipient, "news@a.mx.bulk.example"));
+        assert!(is_blocked(recipient, "news@ok.bulk.example"));
+        assert!(!is_blocked(recipient, "news@a.ok.bulk.example"));
+
+        // exceptions of a pattern entry
+        assert!(is_blocked(recipient, "news-1@lists.example"));
+        assert!(!is_blocked(recipient, "news-keep@lists.example"));
+        assert!(!is_blocked(recipient, "news-vip@lists.example"));
+        assert!(!is_blocked(recipient, "news-vip2@lists.example"));
+
+        // exception does not affect less specific recipient keys
+        assert!(is_blocked(recipient, "x@abusive.example"));
+    }
+
+    #[test]
+    fn test_unreachable_exceptions() {
+        let list = |values: &[&str]| -> SenderList {
+            values.iter().filter_map(|v| Entry::parse(v)).collect()
+        };
+        let unreachable = |list: &SenderList| -> Vec<String> {
+            let mut entries: Vec<String> = list
+                .unreachable_exceptions()
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect();
+            entries.sort();
+            entries
+        };
+
+        let covered = list(&[
+            "@bigcorp.example",
+            "!boss@bigcorp.example",
+            "*.bulk.example",
+            "!@mx.bulk.example",
+            "!*.ok.bulk.example",
+            "news-*@lists.example",
+            "!news-keep@lists.example",
+            "!/^news-vip$/",
+            "!list:quiet.lists.example",
+        ]);
+        assert_eq!(unreachable(&covered), Vec::<String>::new());
+
+        let never = list(&[
+            "boss@bigcorp.example",
+            "!@bigcorp.example",
+            "!boss@bigcorp.example",
+            "!other@example.org",
+            "!*.bulk.example",
+            "!news-*@lists.example",
+            "!list:dev.lists.example",
+        ]);
+        assert_eq!(
+            unreachable(&never),
+            vec![
+                "!*.bulk.example",
+                "!@bigcorp.example",
+                "!boss@bigcorp.example",
+                "!list:dev.lists.example",
+                "!news-*@lists.example",
+                "!other@example.org",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_network_entries() {
+        load_test_maps();
+        let recipient = "hosts@example.com";
+        let origin = |client_ip: &str| Origin {
+            sender: "anyone@example.org",
+            client_ip: client_ip.parse().ok(),
+            ..Default::default()
+        };
+
+        assert!(origin_blocked(recipient, &origin("192.0.2.10")));
+        assert!(origin_blocked(recipient, &origin("::ffff:192.0.2.10")));
+        assert!(origin_blocked(recipient, &origin("2001:db8:1::25")));
+        assert!(origin_blocked(recipient, &origin("198.51.100.7")));
+        assert!(!origin_blocked(recipient, &origin("198.51.100.8")));
+        assert!(!origin_blocked(recipient, &origin("192.0.3.1")));
+        // longest prefix wins, exception of a range
+        assert!(!origin_blocked(recipient, &origin("192.0.2.5")));
+        assert!(origin_allowed(recipient, &origin("203.0.113.9")));
+        // no client address
+        assert!(!origin_blocked(recipient, &origin("")));
+
+        // sender address exception is more specific than client network
+        let origin = Origin {
+            sender: "friend@example.org",
+            ..origin("192.0.2.10")
+        };
+        assert!(origin_allowed(recipient, &origin));
+    }
+
+    #[test]
+    fn test_parse_network_entries() {
+        assert_eq!(parse_entry("192.0.2.1"), Some("192.0.2.1".to_owned()));
+        assert_eq!(
+            parse_entry("192.0.2.77/24"),
+            Some("192.0.2.0/24".to_owned())
+        );
+        assert_eq!(
+            parse_entry("[2001:DB8::1]"),
+            Some("2001:db8::1".to_owned())
+        );
+        assert_eq!(
+            parse_entry("2001:db8::/32"),
+            Some("2001:db8::/32".to_owned())
+        );
+        assert_eq!(parse_entry("0.0.0.0/0"), Some("0.0.0.0/0".to_owned()));
+        assert_eq!(parse_entry("!192.0.2.5"), Some("!192.0.2.5".to_owned()));
+        // invalid prefix is an address value
+        assert_eq!(
+            parse_entry("192.0.2.0/33"),
+            Some("192.0.2.0/33".to_owned())
+        );
+
+        let list: SenderList = ["192.0.2.0/24", "!192.0.2.5", "!10.0.0.1"]
+            .iter()
+            .filter_map(|v| Entry::parse(v))
+            .collect();
+        let unreachable: Vec<String> = list
+            .unreachable_exceptions()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(unreachable, vec!["!10.0.0.1"]);
+    }
+
+    #[test]
+    fn test_host_entries() {
+        load_test_maps();
+        let recipient = "clients@example.com";
+        let origin = |client_name, helo| Origin {
+            sender: "anyone@example.org",
+            client_name,
+            helo,
+            ..Default::default()
+        };
+
+        let bulk = Some("mta1.bulkmailer.example");
+        assert!(origin_blocked(recipient, &origin(bulk, None)));
+        let bulk = Some("MTA1.BulkMailer.example.");
+        assert!(origin_blocked(recipient, &origin(bulk, None)));
+        let bulk = Some("bulkmailer.example");
+        assert!(!origin_blocked(recipient, &origin(bulk, None)));
+        // host exception is more specific than the host glob
+        let bulk = Some("ok.bulkmailer.example");
+        assert!(!origin_blocked(recipient, &origin(bulk, None)));
+        let localhost = Some("localhost");
+        assert!(origin_blocked(recipient, &origin(None, localhost)));
+        let dynamic = Some("host-1.dyn.example");
+        assert!(origin_blocked(recipient, &origin(None, dynamic)));
+        let mailchimp = Some("mail1.mailchimpapp.net");
+        let mx = Some("mx.example");
+        assert!(origin_allowed(recipient, &origin(mailchimp, mx)));
+        // block list is checked before allow list
+        assert!(origin_blocked(recipient, &origin(mailchimp, localhost)));
+        let partner = Some("mx.partner.example");
+        assert!(origin_allowed(recipient, &origin(None, partner)));
+        assert!(!origin_blocked(recipient, &origin(None, None)));
+        assert!(!origin_allowed(recipient, &origin(None, None)));
+
+        // sender address exception is more specific than client entries
+        let origin = Origin {
+            sender: "bob@example.org",
+            ..origin(None, localhost)
+        };
+        assert_eq!(lookup_origin(recipient, &origin), Verdict::NoMatch);
+    }
+
+    #[test]
+    fn test_list_entries() {
+        load_test_maps();
+        let recipient = "lists@example.com";
+        let origin = |list_id, list_post| Origin {
+            sender: "anyone@example.org",
+            list_id,
+            list_post,
+            ..Default::default()
+        };
+
+        let noisy = Some("noisy.lists.example.org");
+        assert!(origin_blocked(recipient, &origin(noisy, None)));
+        let noisy_upper = Some("Noisy.Lists.Example.org.");
+        assert!(origin_blocked(recipient, &origin(noisy_upper, None)));
+        let chatter = Some("chatter@lists.example.org");
+        assert!(origin_blocked(recipient, &origin(None, chatter)));
+        let other = Some("other.lists.example.org");
+        let chatter_upper = Some("Chatter@lists.example.org");
+        assert!(origin_blocked(recipient, &origin(other, chatter_upper)));
+        let dev = Some("dev.lists.example.org");
+        assert!(origin_allowed(recipient, &origin(dev, None)));
+        // block list is checked before allow list
+        assert!(origin_blocked(recipient, &origin(dev, chatter)));
+        let lists = Some("lists.example.org");
+        let dev_post = Some("dev@lists.example.org");
+        assert!(!origin_blocked(recipient, &origin(lists, dev_post)));
+        assert!(!origin_blocked(recipient, &origin(None, None)));
+
+        // sender address exception is more specific than list entries
+        let origin = Origin {
+            sender: "boss@example.org",
+            ..origin(noisy, None)
+        };
+        assert_eq!(lookup_origin(recipient, &origin), Verdict::NoMatch);
+
+        assert_eq!(
+            parse_entry("list:Dev.Lists.Example.org."),
+            Some("list:dev.lists.example.org".to_owned())
+        );
+        assert_eq!(
+            parse_entry("list:Dev@Lists.Example.org"),
+            Some("list:dev@lists.example.org".to_owned())
+        );
+        assert_eq!(parse_entry("list:"), None);
+    }
+
+    #[test]
+    fn test_parse_host_entries() {
+        assert_eq!(
+            parse_entry("host:*.MailChimpApp.net"),
+            Some("host:*.mailchimpapp.net".to_owned())
+        );
+        assert_eq!(
+            parse_entry("!helo:mx.example.net."),
+            Some("!helo:mx.example.net".to_owned())
+        );
+        assert_eq!(
+            parse_entry("helo:mx.Bücher.example"),
+            Some("helo:mx.xn--bcher-kva.example".to_owned())
+        );
+        assert_eq!(parse_entry("host:"), None);
+
+        let list: SenderList = ["!host:ok.example", "!helo:ok.example"]
+            .iter()
+            .filter_map(|value| Entry::parse(value))
+            .collect();
+        let mut unreachable: Vec<String> = list
+            .unreachable_exceptions()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        unreachable.sort();
+        assert_eq!(unreachable, vec!["!helo:ok.example", "!host:ok.example"]);
+
+        let entries = ["helo:*.example", "!host:a.example", "!10.0.0.1"];
+        let list: SenderList = entries
+            .iter()
+            .filter_map(|value| Entry::parse(value))
+            .collect();
+        assert!(list.unreachable_exceptions().is_empty());
+    }
+
+    #[test]
+    fn test_recipient_delimiter() {
+        load_test_maps();
+        let blocked = |recipient, sender, options| {
+            let verdict = lookup_sender(recipient, sender, options);
+            matches!(verdict, Verdict::Blocked(_))
+        };
+        let (rcpt, kale) = ("tagged@example.com", "kale@example.org");
+        let options = AddressOptions::default();
+        assert!(!blocked("tagged+shop@example.com", kale, &options));
+
+        let envelope = SenderMatch::Envelope;
+        let options = address_options(Some("+-"), false, envelope);
+        // detail part is stripped from recipient
+        assert!(blocked("tagged+shop@example.com", kale, &options));
+        assert!(blocked("Tagged-x@example.com", kale, &options));
+        // tagged sender does not match untagged entry
+        assert!(!blocked(rcpt, "kale+x@example.org", &options));
+        // tagged entry matches tagged sender only
+        assert!(blocked(rcpt, "kale+news@example.org", &options));
+        assert!(!blocked(rcpt, "kale+x@example.net", &options));
+
+        let options = address_options(Some("+"), true, envelope);
+        // untagged entry matches sender with or without the tag
+        assert!(blocked(rcpt, "kale+x@example.org", &options));
+        assert!(blocked(rcpt, kale, &options));
+        assert!(blocked(rcpt, "kale+news@example.org", &options));
+    }
+
+    #[test]
+    fn test_encoded_sender() {
+        load_test_maps();
+        let blocked = |sender, options: &AddressOptions| {
+            let verdict = lookup_sender("reanna@example.com", sender, options);
+            matches!(verdict, Verdict::Blocked(_))
+        };
+        let srs = "SRS0=HHH=TT=example.org=kale@forwarder.example";
+        let batv = "prvs=1234abcd=kale@example.org";
+        let options = AddressOptions::default().without_decoders();
+        assert!(!blocked(srs, &options));
+        assert!(!blocked(batv, &options));
+
+        let options = AddressOptions::default();
+        assert!(blocked(srs, &options));
+        assert!(blocked(batv, &options));
+        let other = "SRS0=HHH=TT=example.org=kale2@forwarder.example";
+        assert!(!blocked(other, &options));
+
+        // decoded sender of an unauthenticated domain is not allowed
+        let origin = Origin {
+            sender: "SRS0=HHH=TT=example.org=taurean@forwarder.example",
+            ..Default::default()
+        };
+        assert!(origin_allowed("teresa@example.com", &origin));
+        let origin = Origin {
+            skip_decoded_allow: true,
+            ..origin
+        };
+        assert!(!origin_allowed("teresa@example.com", &origin));
+        let origin = Origin {
+            sender: srs,
+            skip_decoded_allow: true,
+            ..Default::default()
+        };
+        assert!(origin_blocked("reanna@example.com", &origin));
+    }
+
+    #[test]
+    fn test_null_sender() {
+        load_test_maps();
+        let options = AddressOptions::default();
+        let matched = |recipient, sender| {
+            match lookup_sender(recipient, sender, &options) {
+                Verdict::Blocked(m) => Some(("block", m.entry.to_string())),
+                Verdict::Allowed(m) => Some(("allow", m.entry.to_string())),
+                Verdict::NoMatch => None,
+            }
+        };
+
+        assert_eq!(
+            matched("bounces@example.com", ""),
+            Some(("block", "<>".to_owned()))
+        );
+        assert_eq!(
+            matched("bounces@example.net", ""),
+            Some(("allow", "<>".to_owned()))
+        );
+        assert_eq!(matched("reanna@example.com", ""), None);
+        // `<>` does not match any address
+        assert_eq!(
+            matched("bounces@example.com", "mailer-daemon@example.org"),
+            None
+        );
+        assert_eq!(matched("bounces@example.net", "x@example.org"), None);
+
+        assert_eq!(parse_entry("<>"), Some("<>".to_owned()));
+    }
+
+    #[test]
+    fn test_impersonated_contact() {
+        load_test_maps();
+        let options = AddressOptions::default();
+        let rcpt = "impersonated@example.com";
+        let impersonated = |recipient, name, from| {
+            impersonated_contact(recipient, name, from, &options)
+                .map(|m| (m.key, m.entry.to_string()))
+        };
+        let jane =
+            Some((rcpt.to_owned(), "jane.cfo@partner.example".to_owned()));
+
+        let evil = "jane@evil.example";
+        assert_eq!(impersonated(rcpt, "Jane CFO", evil), jane);
+        assert_eq!(impersonated(rcpt, "cfo, jane", "x@partner.example"), jane);
+        assert_eq!(impersonated(rcpt, "jane.cfo@partner.example", evil), jane);
+        // the contact itself or another contact of the recipient
+        let contact = "Jane.CFO@partner.example";
+        assert_eq!(impersonated(rcpt, "Jane CFO", contact), None);
+        assert_eq!(impersonated(rcpt, "Jane CFO", "bob@example.org"), None);
+        assert_eq!(impersonated(rcpt, "Jane", evil), None);
+        // domain entries are not contacts
+        assert_eq!(impersonated(rcpt, "Partner", "x@evil.example"), None);
+        // not a contact of this recipient
+        assert_eq!(impersonated("reanna@example.com", "Jane CFO", evil), None);
+    }
+
+    #[test]
+    fn test_header_from() {
+        load_test_maps();
+        let blocked = |sender, header_from, options: &AddressOptions| {
+            let origin = Origin {
+                sender,
+                header_from,
+                ..Default::default()
+            };
+            let verdict = lookup("headers@example.com", &origin, options);
+            matches!(verdict, Verdict::Blocked(_))
+        };
+
+        let options = AddressOptions::default();
+        assert!(blocked("bounce@esp.example", None, &options));
+        assert!(!blocked(
+            "x@example.org",
+            Some("Bounce <bounce@esp.example>"),
+            &options
+        ));
+        // `match=header` entry
+        assert!(!blocked("x@news.example", None, &options));
+        assert!(blocked(
+            "bounce@esp.example",
+            Some("x@news.example"),
+            &options
+        ));
+        assert!(blocked(
+            "x@example.org",
+            Some("x@sub.news.example"),
+            &options
+        ));
+        // `match=both` entry
+        assert!(blocked("newsletter@shop.example", None, &options));
+        assert!(blocked(
+            "x@example.org",
+            Some("newsletter@Shop.example"),
+            &options
+        ));
+
+        let options = address_options(None, false, SenderMatch::Header);
+        assert!(!blocked("bounce@esp.example", None, &options));
+        assert!(blocked(
+            "x@example.org",
+            Some("bounce@esp.example"),
+            &options
+        ));
+        assert!(blocked("x@news.example", Some("x@news.example"), &options));
+
+        let options = address_options(None, false, SenderMatch::Both);
+        assert!(blocked("bounce@esp.example", None, &options));
+        assert!(blocked(
+            "x@example.org",
+            Some("bounce@esp.example"),
+            &options
+        ));
+        // `match=header` entry is not matched against the envelope sender
+        assert!(!blocked("x@news.example", Some("x@example.org"), &options));
+    }
+
+    #[test]
+    fn test_lookup_outbound() {
+        load_test_maps();
+        let options = AddressOptions::default();
+        let blocked = |sender, recipient| {
+            lookup_outbound(sender, recipient, &options)
+                .map(|m| m.entry.to_string())
+        };
+        assert_eq!(
+            blocked("Reanna@example.com", "KALE@example.org"),
+            Some("kale@example.org".to_owned())
+        );
+        assert_eq!(blocked("kale@example.org", "reanna@example.com"), None);
+        assert_eq!(
+            blocked("exceptions@example.com", "anyone@bigcorp.example"),
+            Some("@bigcorp.example".to_owned())
+        );
+        assert_eq!(
+            blocked("exceptions@example.com", "boss@bigcorp.example"),
+            None
+        );
+        // `match=header` entries apply too
+        assert_eq!(
+            blocked("headers@example.com", "info@news.example"),
+            Some("@news.example".to_owned())
+        );
+        // domain and global keys do not apply
+        assert_eq!(blocked("anyone@corp.example", "pest@example.org"), None);
+        assert_eq!(blocked("anyone@example.net", "abuser@example.org"), None);
+
+        let options = address_options(Some("+"), false, SenderMatch::Envelope);
+        let matched = lookup_outbound(
+            "reanna+x@example.com",
+            "kale@example.org",
+            &options,
+        );
+        assert_eq!(
+            matched.map(|m| m.key),
+            Some("reanna@example.com".to_owned())
+        );
+    }
+
     #[test]
     fn test_should_update() {
         let path = "tests/test.map";
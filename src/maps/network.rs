@@ -0,0 +1,99 @@
+//! Postkeeper client IP address and CIDR range map values
+
+use std::fmt;
+use std::net::IpAddr;
+
+/// An IPv4/IPv6 address or CIDR range of sending hosts
+///
+/// EXAMPLE:
+///  `192.0.2.1`       single IPv4 address
+///  `192.0.2.0/24`    IPv4 range
+///  `[2001:db8::1]`   single IPv6 address, brackets are optional
+///  `2001:db8::/32`   IPv6 range
+#[derive(Debug, Clone, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// parses an address or range, host bits of a range are cleared
+    /// returns None if value is not an IP address or prefix is too long
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value
+            .strip_prefix('[')
+            .and_then(|v| v.strip_suffix(']'))
+            .unwrap_or(value);
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+
+        let addr: IpAddr = addr.parse().ok()?;
+        let max = max_prefix(addr);
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= max)?,
+            None => max,
+        };
+
+        Some(Self {
+            addr: mask(addr, prefix),
+            prefix,
+        })
+    }
+
+    /// prefix length, longer is more specific
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// true if `ip` is in this range, IPv4-mapped IPv6 addresses
+    /// (`::ffff:192.0.2.1`) match their IPv4 range
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        match (self.addr, ip) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                mask(ip, self.prefix) == self.addr
+            }
+            _ => false,
+        }
+    }
+
+    /// true if `other` range is inside this range
+    pub fn covers(&self, other: &Network) -> bool {
+        self.prefix <= other.prefix && self.contains(other.addr)
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix == max_prefix(self.addr) {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+// clears the host bits of the address
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix));
+            IpAddr::from((bits & mask.unwrap_or(0)).to_be_bytes())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix));
+            IpAddr::from((bits & mask.unwrap_or(0)).to_be_bytes())
+        }
+    }
+}
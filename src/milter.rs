@@ -39,16 +39,34 @@
 //! Further detail on this and on the high-level design of the milter library
 //! can be found in its [documentation](https://salsa.debian.org/debian/sendmail/tree/master/libmilter/docs).
 
-use crate::config::{global_conf, init_global_conf, Config};
+use crate::address::{
+    header_address, header_display_name, list_id, list_post_address,
+    smtp_address,
+};
+use crate::config::{
+    global_conf, init_global_conf, AuthenticatedPolicy, BlockAction, Config,
+    ImpersonationAction, NullSenderPolicy, OutboundAction,
+};
 use crate::consts::*;
-use crate::maps::{is_allowed, is_blocked, load_maps_if_changed};
+use crate::header::{HeaderFields, HeaderTemplate};
+use crate::maps::{
+    impersonated_contact, load_maps_if_changed, lookup, lookup_outbound, Entry,
+    Match, Origin, Verdict, GLOBAL_KEY,
+};
+use crate::session::Session;
 use milter::*;
-use std::{net::SocketAddr, process};
+use std::net::SocketAddr;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// set once an envelope address has been taken from SMTP arguments
+static SMTP_ARGS_USED: AtomicBool = AtomicBool::new(false);
 
 /// invoked on first interation between MTA and the milter
 #[on_negotiate(negotiate_callback)]
 fn handle_negotiate(
-    ctx: Context<()>,
+    ctx: Context<Session>,
     actions: Actions,
     protocol_opts: ProtocolOpts,
 ) -> milter::Result<(Status, Actions, ProtocolOpts)> {
@@ -57,17 +75,41 @@ fn handle_negotiate(
     log::trace!("Actions: {:?}", actions);
     log::trace!("Protocol options: {:?}", protocol_opts);
 
+    // logged for every negotiation, the source can differ per MTA. An MTA
+    // accepting the request may still not send the macros, the fallback is
+    // then reported by [`envelope_address`]
+    if actions.contains(Actions::REQUEST_MACROS) {
+        log::debug!(
+            "MTA accepts macro requests, requesting {} and {}",
+            MACRO_SENDER_ADDR,
+            MACRO_RECPT_ADDR
+        );
+    } else {
+        log::warn!(
+            "MTA does not accept macro requests, envelope addresses are taken \
+            from MAIL FROM and RCPT TO arguments unless it is configured to \
+            send {} and {}",
+            MACRO_SENDER_ADDR,
+            MACRO_RECPT_ADDR
+        );
+    }
+
     // ask MTA to send sender and recipient addresses on following stages
-    ctx.api.request_macros(Stage::Mail, MACRO_SENDER_ADDR)?;
+    // and the SASL login name of authenticated clients
+    let mail_macros = format!("{} {}", MACRO_SENDER_ADDR, MACRO_AUTH_AUTHEN);
+    ctx.api.request_macros(Stage::Mail, &mail_macros)?;
     ctx.api.request_macros(Stage::Rcpt, MACRO_RECPT_ADDR)?;
 
     Ok((Status::AllOpts, Default::default(), Default::default()))
 }
 
 /// on_connect calback (returns -> Continue)
+/// creates the session data for this connection, keeps the client address
+/// and its verified hostname (MTA sends `unknown` or an address literal if
+/// reverse DNS lookup failed)
 #[on_connect(connect_callback)]
 fn handle_connect(
-    _ctx: Context<()>,
+    mut ctx: Context<Session>,
     hostname: &str,
     socket_address: Option<SocketAddr>,
 ) -> milter::Result<Status> {
@@ -83,66 +125,159 @@ fn handle_connect(
     log::debug!("Try load maps if changed");
     load_maps_if_changed();
 
+    let client_name = Some(hostname)
+        .filter(|name| !name.is_empty() && *name != "unknown")
+        .filter(|name| !name.starts_with('['))
+        .map(str::to_owned);
+    ctx.data.replace(Session {
+        client_ip: socket_address.map(|addr| addr.ip().to_canonical()),
+        client_name,
+        ..Default::default()
+    })?;
+
     Ok(Status::Continue)
 }
 
 /// on_helo calback (returns -> Continue)
+/// keeps the HELO name, a client can greet more than once per connection
 #[on_helo(helo_callback)]
-fn handle_helo(_ctx: Context<()>, _helo_host: &str) -> milter::Result<Status> {
+fn handle_helo(
+    mut ctx: Context<Session>,
+    helo_host: &str,
+) -> milter::Result<Status> {
     log::trace!("Stage: HELO");
 
+    log::trace!("helo_host: {}", helo_host);
+    let session = session(&mut ctx.data)?;
+    session.helo = Some(helo_host.to_owned()).filter(|name| !name.is_empty());
+
     Ok(Status::Continue)
 }
 
 /// on_mail calback (returns -> Continue)
+/// starts a new message in session and stores its sender and SASL login name
+/// if the client is authenticated
 #[on_mail(mail_callback)]
 fn handle_mail(
-    _ctx: Context<()>,
-    _smtp_args: Vec<&str>,
+    mut ctx: Context<Session>,
+    smtp_args: Vec<&str>,
 ) -> milter::Result<Status> {
     log::trace!("Stage: MAIL");
 
+    let sender = get_sender(&ctx.api, &smtp_args);
+    // macro is only sent for SASL authenticated clients
+    let auth_user = match ctx.api.macro_value(MACRO_AUTH_AUTHEN) {
+        Ok(Some(user)) if !user.is_empty() => {
+            log::debug!("found {}: `{}`", MACRO_AUTH_AUTHEN, user);
+            Some(user.to_owned())
+        }
+        _ => None,
+    };
+    let session = session(&mut ctx.data)?;
+    session.reset_message();
+    session.sender = sender;
+    session.auth_user = auth_user;
+
     Ok(Status::Continue)
 }
 
-/// on_rcpt calback (returns -> Continue)
+/// on_rcpt calback (returns -> Continue, Reject or Tempfail)
+/// collects every recipient of the message in session
+/// recipients who have blocked the sender are rejected right away if
+/// configured, see [`process_recipient`]
 #[on_rcpt(rcpt_callback)]
 fn handle_rcpt(
-    ctx: Context<()>,
-    _smtp_args: Vec<&str>,
+    mut ctx: Context<Session>,
+    smtp_args: Vec<&str>,
 ) -> milter::Result<Status> {
     log::trace!("Stage: RCPT");
     print_macros(&ctx.api);
+
+    let recipient = envelope_address(&ctx.api, MACRO_RECPT_ADDR, &smtp_args)
+        .filter(|recipient| !recipient.is_empty());
+    if let Some(recipient) = recipient {
+        let session = session(&mut ctx.data)?;
+        let status =
+            process_recipient(&ctx.api, session, &recipient, global_conf())?;
+        if status == Status::Continue {
+            session.recipients.push(recipient);
+        }
+        return Ok(status);
+    }
+
     Ok(Status::Continue)
 }
 
 /// on_data calback (returns -> Continue)
 #[on_data(data_callback)]
-fn handle_data(_ctx: Context<()>) -> milter::Result<Status> {
+fn handle_data(_ctx: Context<Session>) -> milter::Result<Status> {
     log::trace!("Stage: DATA");
 
     Ok(Status::Continue)
 }
 
-/// on_headers calback (returns -> Skip)
-/// we don't need to process headers
+/// on_headers calback (returns -> Continue)
+/// counts incoming allow and block headers, these are removed at EOM stage so
+/// that a sender can not forge them, see [`strip_decision_headers`]
+/// keeps the Subject for `tag` action, the From address, the mailing list
+/// and trusted authentication results
 #[on_header(header_callback)]
 fn handle_header(
-    _ctx: Context<()>,
+    mut ctx: Context<Session>,
     name: &str,
     value: &str,
 ) -> milter::Result<Status> {
     log::trace!("Stage: HEADER");
     log::trace!("header {}: {}", name, value);
 
-    // skip future calls to this callback
-    // we don't care about headers
-    Ok(Status::Skip)
+    if name.eq_ignore_ascii_case(global_conf().allow_header().name()) {
+        log::debug!("Found incoming header {}: {}", name, value);
+        session(&mut ctx.data)?.allow_headers += 1;
+    } else if name.eq_ignore_ascii_case(global_conf().block_header().name()) {
+        log::debug!("Found incoming header {}: {}", name, value);
+        session(&mut ctx.data)?.block_headers += 1;
+    } else if name.eq_ignore_ascii_case("Subject") {
+        let session = session(&mut ctx.data)?;
+        if session.subject.is_none() {
+            session.subject = Some(value.to_owned());
+        }
+    } else if name.eq_ignore_ascii_case("From") {
+        let session = session(&mut ctx.data)?;
+        if session.header_from.is_none() {
+            session.header_from = header_address(value);
+            session.header_from_name = header_display_name(value);
+            log::debug!(
+                "From header address {:?}, display name {:?}",
+                session.header_from,
+                session.header_from_name
+            );
+        }
+    } else if name.eq_ignore_ascii_case("List-Id") {
+        let session = session(&mut ctx.data)?;
+        if session.list_id.is_none() {
+            session.list_id = list_id(value);
+            log::debug!("List-Id {:?}", session.list_id);
+        }
+    } else if name.eq_ignore_ascii_case("List-Post") {
+        let session = session(&mut ctx.data)?;
+        if session.list_post.is_none() {
+            session.list_post = list_post_address(value);
+            log::debug!("List-Post address {:?}", session.list_post);
+        }
+    } else if name.eq_ignore_ascii_case("Authentication-Results") {
+        let trusted = global_conf().authserv_ids();
+        session(&mut ctx.data)?
+            .authentication
+            .add_header(value, trusted);
+    }
+
+    // every header must be seen, do not return Skip here
+    Ok(Status::Continue)
 }
 
 /// on_eoh calback (returns -> Continue)
 #[on_eoh(eoh_callback)]
-fn handle_eoh(_ctx: Context<()>) -> milter::Result<Status> {
+fn handle_eoh(_ctx: Context<Session>) -> milter::Result<Status> {
     log::trace!("Stage: EOH");
 
     Ok(Status::Continue)
@@ -150,7 +285,10 @@ fn handle_eoh(_ctx: Context<()>) -> milter::Result<Status> {
 
 /// on_body calback (returns -> Continue)
 #[on_body(body_callback)]
-fn handle_body(_ctx: Context<()>, _content: &[u8]) -> milter::Result<Status> {
+fn handle_body(
+    _ctx: Context<Session>,
+    _content: &[u8],
+) -> milter::Result<Status> {
     log::trace!("Stage: BODY");
 
     Ok(Status::Continue)
@@ -158,62 +296,53 @@ fn handle_body(_ctx: Context<()>, _content: &[u8]) -> milter::Result<Status> {
 
 /// on_eom calback
 /// end of message: on this callback we try to find the match if a given sender
-/// is in `allow-list` or in `block-list` for the recipient
-/// if blocked configured status will be returned
-/// if allowed, messages is accepted and a custom header is added.
+/// is in `allow-list` or in `block-list` for each recipient of the message
+/// see [`process_message`]
 #[on_eom(eom_callback)]
-fn handle_eom(ctx: Context<()>) -> milter::Result<Status> {
+fn handle_eom(mut ctx: Context<Session>) -> milter::Result<Status> {
     log::info!("Stage: EOM");
 
     print_macros(&ctx.api);
-    if let Some((recipient, sender)) = get_recipient_and_sender(&ctx.api) {
-        if is_blocked(recipient, sender) {
-            let status = global_conf().on_block_action();
-            log::debug!(
-                "Applying config on_block_action '{:?}' to Sender '{}' for '{}'",
-                status,
-                sender,
-                recipient
-            );
-            return Ok(status);
-        } else {
-            log::info!("Block match not found");
+    match ctx.data.borrow_mut() {
+        Some(session) => {
+            let status = process_message(&ctx.api, session, global_conf());
+            session.reset_message();
+            status
         }
-
-        if is_allowed(recipient, sender) {
-            log::debug!(
-                "Adding Postkeeper Header for sender '{}', recipient '{}'",
-                sender,
-                recipient
-            );
-            ctx.api.add_header(POSTKEEPER_HEADER, "Yes")?;
-            // accept the this message
-            return Ok(Status::Accept);
-        } else {
-            log::info!("Allow match not found");
+        None => {
+            log::warn!("Session data is missing, skip processing message");
+            Ok(Status::Continue)
         }
-    };
-    Ok(Status::Continue)
+    }
 }
 
 /// on_abort calback (returns -> Continue)
+/// discards the current message from session
 #[on_abort(abort_callback)]
-fn handle_abort(ctx: Context<()>) -> milter::Result<Status> {
+fn handle_abort(mut ctx: Context<Session>) -> milter::Result<Status> {
     log::warn!("Stage: ABORT");
     print_macros(&ctx.api);
+    if let Some(session) = ctx.data.borrow_mut() {
+        session.reset_message();
+    }
     Ok(Status::Continue)
 }
 
 /// on_close calback (returns -> Continue)
+/// releases the session data of this connection
 #[on_close(close_callback)]
-fn handle_close(_ctx: Context<()>) -> milter::Result<Status> {
+fn handle_close(mut ctx: Context<Session>) -> milter::Result<Status> {
     log::info!("Stage: CLOSE");
+    ctx.data.take()?;
     Ok(Status::Continue)
 }
 
 /// on_unknown calback (returns -> Continue)
 #[on_unknown(unknown_callback)]
-fn handle_unknown(ctx: Context<()>, smtp_cmd: &str) -> milter::Result<Status> {
+fn handle_unknown(
+    ctx: Context<Session>,
+    smtp_cmd: &str,
+) -> milter::Result<Status> {
     log::info!("Stage: UNKNOWN");
     log::trace!("smtp_cmd: {}", smtp_cmd);
     print_macros(&ctx.api);
@@ -221,39 +350,638 @@ fn handle_unknown(ctx: Context<()>, smtp_cmd: &str) -> milter::Result<Status> {
     Ok(Status::Continue)
 }
 
-/// try get recipient and sender from milter contex api
-/// returns Some(recipient, sender) if successful
-/// None if both values cannot be acquired from MTA macros
-pub fn get_recipient_and_sender(
+/// returns the session of this connection from context data
+/// creates a new session if connect callback did not create one
+fn session(data: &mut DataHandle<Session>) -> milter::Result<&mut Session> {
+    if data.borrow().is_none() {
+        log::debug!("Session data is missing, creating a new session");
+        data.replace(Session::default())?;
+    }
+    Ok(data.borrow_mut().expect("session data is initialized"))
+}
+
+/// try get an email address from given MTA macro
+/// returns None if the MTA did not send the macro
+pub fn get_macro_address(
     ctx_api: &impl MacroValue,
-) -> Option<(&str, &str)> {
-    let recipient = match ctx_api.macro_value(MACRO_RECPT_ADDR) {
-        Ok(recipient) => recipient,
+    name: &str,
+) -> Option<String> {
+    match ctx_api.macro_value(name) {
+        Ok(Some(address)) => {
+            log::debug!("found {}: `{}`", name, address);
+            Some(address.to_owned())
+        }
+        Ok(None) => {
+            log::debug!("Macro {} is missing", name);
+            None
+        }
         Err(e) => {
-            log::warn!("Could not get recipient from macro, {:?}", e);
+            log::warn!("Could not get address from macro {}, {:?}", name, e);
             None
         }
+    }
+}
+
+/// what is known about the current message of the session
+fn origin<'a>(session: &'a Session, sender: &'a str) -> Origin<'a> {
+    Origin {
+        sender,
+        header_from: session.header_from.as_deref(),
+        client_ip: session.client_ip,
+        client_name: session.client_name.as_deref(),
+        helo: session.helo.as_deref(),
+        list_id: session.list_id.as_deref(),
+        list_post: session.list_post.as_deref(),
+        skip_allow: false,
+        skip_decoded_allow: false,
+    }
+}
+
+/// how the current message is checked, `check` unless the client is SASL
+/// authenticated, see [`AuthenticatedPolicy`]
+fn sender_policy(session: &Session, config: &Config) -> AuthenticatedPolicy {
+    match session.auth_user {
+        Some(_) => config.authenticated_senders(),
+        None => AuthenticatedPolicy::Check,
+    }
+}
+
+/// true if the envelope sender and the From header address (if known) are
+/// aligned with a passing SPF or DKIM result of the message
+fn is_authenticated(session: &Session, sender: &str) -> bool {
+    let authentication = &session.authentication;
+    authentication.is_aligned(sender)
+        && session
+            .header_from
+            .as_deref()
+            .is_none_or(|from| authentication.is_aligned(from))
+}
+
+/// true if the decoded envelope sender and From header address are aligned
+/// like [`is_authenticated`], addresses that are not encoded are ignored
+/// a forwarder can authenticate its SRS address, not the original sender
+fn is_decoded_authenticated(
+    session: &Session,
+    sender: &str,
+    config: &Config,
+) -> bool {
+    let options = config.address_options();
+    std::iter::once(sender)
+        .chain(session.header_from.as_deref())
+        .filter_map(|address| options.decode(address))
+        .all(|decoded| session.authentication.is_aligned(&decoded))
+}
+
+/// address of the MAIL or RCPT stage from given macro, or from the SMTP
+/// command arguments if the MTA did not send the macro
+/// the first fallback to arguments of the process is logged as a warning
+/// naming the command, the MTA is not set up to send the macro
+/// returns None and logs a warning if neither has an address
+fn envelope_address(
+    ctx_api: &impl MacroValue,
+    name: &str,
+    smtp_args: &[&str],
+) -> Option<String> {
+    if let Some(address) = get_macro_address(ctx_api, name) {
+        return Some(address);
+    }
+
+    let address = match smtp_address(smtp_args) {
+        Some(address) => address,
+        None => {
+            log::warn!("Macro {} and SMTP arguments are missing", name);
+            return None;
+        }
     };
+    if !SMTP_ARGS_USED.swap(true, Ordering::Relaxed) {
+        let command = match name {
+            MACRO_SENDER_ADDR => "MAIL FROM",
+            _ => "RCPT TO",
+        };
+        log::warn!(
+            "Macro {} is missing, using {} arguments {:?} instead",
+            name,
+            command,
+            smtp_args
+        );
+    }
+    log::debug!("found {} in SMTP arguments: `{}`", name, address);
+    Some(address)
+}
 
-    let sender = match ctx_api.macro_value(MACRO_SENDER_ADDR) {
-        Ok(sender) => sender,
-        Err(e) => {
-            log::warn!("Could not get sender from macro, {:?}", e);
-            None
+/// envelope sender of the message from `{mail_addr}` macro or `MAIL FROM`
+/// arguments, the null sender (`MAIL FROM:<>`) is an empty address
+/// returns None if neither has the sender
+pub fn get_sender(
+    ctx_api: &impl MacroValue,
+    smtp_args: &[&str],
+) -> Option<String> {
+    let sender = envelope_address(ctx_api, MACRO_SENDER_ADDR, smtp_args)?;
+    if sender.is_empty() || sender == NULL_SENDER {
+        log::debug!("Message has the null sender");
+        return Some(String::new());
+    }
+    Some(sender)
+}
+
+/// true if the message has the null sender and `null_sender = bypass`
+fn bypasses_null_sender(sender: &str, config: &Config) -> bool {
+    sender.is_empty() && config.null_sender() == NullSenderPolicy::Bypass
+}
+
+/// looks up the lists of a recipient, with `null_sender = block` a message
+/// with the null sender is blocked if neither list of the recipient has a
+/// match for it
+fn recipient_verdict(
+    recipient: &str,
+    origin: &Origin,
+    config: &Config,
+) -> Verdict {
+    let verdict = lookup(recipient, origin, config.address_options());
+    if verdict == Verdict::NoMatch
+        && origin.sender.is_empty()
+        && config.null_sender() == NullSenderPolicy::Block
+    {
+        log::debug!("Null sender is blocked for '{}'", recipient);
+        return Verdict::Blocked(Match {
+            key: GLOBAL_KEY.to_owned(),
+            entry: Entry::null_sender(),
+        });
+    }
+    verdict
+}
+
+/// recipients with an allow listed contact the `From:` display name
+/// impersonates, see [`impersonated_contact`]
+fn impersonations<'a>(
+    session: &'a Session,
+    config: &Config,
+) -> Vec<(&'a str, Match)> {
+    let (name, from) = match (&session.header_from_name, &session.header_from)
+    {
+        (Some(name), Some(from)) => (name, from),
+        _ => return Vec::new(),
+    };
+    let options = config.address_options();
+    session
+        .recipients
+        .iter()
+        .filter_map(|rcpt| {
+            let matched = impersonated_contact(rcpt, name, from, options)?;
+            Some((rcpt.as_str(), matched))
+        })
+        .collect()
+}
+
+/// checks the block list of a single recipient at RCPT stage
+/// returns Reject (or Tempfail) if `reject_at_rcpt` is enabled,
+/// `on_block_action` is reject (or tempfail) and the recipient has blocked the
+/// sender, the MTA then replies with 5xx (or 4xx) to this `RCPT TO` only and
+/// other recipients of the transaction are unaffected.
+/// returns Continue otherwise, the recipient is then evaluated at EOM stage
+fn process_recipient(
+    api: &impl SetErrorReply,
+    session: &Session,
+    recipient: &str,
+    config: &Config,
+) -> milter::Result<Status> {
+    match sender_policy(session, config) {
+        AuthenticatedPolicy::Check => {}
+        AuthenticatedPolicy::Bypass => return Ok(Status::Continue),
+        AuthenticatedPolicy::Outbound => {
+            return process_outbound_recipient(api, session, recipient, config);
         }
+    }
+
+    let action = config.on_block_action();
+    let rejects = matches!(action, BlockAction::Reject | BlockAction::Tempfail);
+    if !config.reject_at_rcpt() || !rejects {
+        return Ok(Status::Continue);
+    }
+
+    let sender = match session.sender.as_deref() {
+        Some(sender) => sender,
+        None => return Ok(Status::Continue),
     };
 
-    if let (Some(recipient), Some(sender)) = (recipient, sender) {
-        log::debug!("found recpt: `{}`, sender: `{}`", recipient, sender);
-        Some((recipient, sender))
-    } else {
+    if bypasses_null_sender(sender, config) {
+        return Ok(Status::Continue);
+    }
+
+    let origin = origin(session, sender);
+    let verdict = recipient_verdict(recipient, &origin, config);
+    if let Verdict::Blocked(matched) = verdict {
+        log::debug!(
+            "Rejecting recipient '{}' at RCPT stage, Sender '{}' is blocked",
+            recipient,
+            sender
+        );
+        set_block_reply(api, config, action, &matched)?;
+        return Ok(action.status());
+    }
+
+    Ok(Status::Continue)
+}
+
+/// checks the block list of an authenticated local sender at RCPT stage
+/// returns Reject if the sender has blocked the recipient and
+/// `on_outbound_block` is reject, see [`lookup_outbound`]
+fn process_outbound_recipient(
+    api: &impl SetErrorReply,
+    session: &Session,
+    recipient: &str,
+    config: &Config,
+) -> milter::Result<Status> {
+    if config.on_outbound_block() != OutboundAction::Reject {
+        return Ok(Status::Continue);
+    }
+
+    let sender = match session.sender.as_deref() {
+        Some(sender) => sender,
+        None => return Ok(Status::Continue),
+    };
+
+    let options = config.address_options();
+    if let Some(matched) = lookup_outbound(sender, recipient, options) {
+        log::info!(
+            "Rejecting recipient '{}', Sender '{}' has blocked it",
+            recipient,
+            sender
+        );
+        set_block_reply(api, config, BlockAction::Reject, &matched)?;
+        return Ok(Status::Reject);
+    }
+
+    Ok(Status::Continue)
+}
+
+/// adds the block header for every recipient an authenticated local sender
+/// has blocked if `on_outbound_block` is warn, the rendered `{list}` is
+/// `outbound`. Recipients lists are not checked
+fn process_outbound_message(
+    api: &impl ActionContext,
+    session: &Session,
+    config: &Config,
+) -> milter::Result<Status> {
+    if config.on_outbound_block() != OutboundAction::Warn {
+        return Ok(Status::Continue);
+    }
+
+    let sender = match session.sender.as_deref() {
+        Some(sender) => sender,
+        None => return Ok(Status::Continue),
+    };
+
+    let options = config.address_options();
+    let blocked: Vec<(&str, Match)> = session
+        .recipients
+        .iter()
+        .filter_map(|rcpt| {
+            let matched = lookup_outbound(sender, rcpt, options)?;
+            log::info!("Sender '{}' has blocked '{}'", sender, rcpt);
+            Some((rcpt.as_str(), matched))
+        })
+        .collect();
+    let blocked: Vec<(&str, &Match)> =
+        blocked.iter().map(|(rcpt, matched)| (*rcpt, matched)).collect();
+    add_headers(api, config.block_header(), "outbound", session, &blocked)?;
+
+    Ok(Status::Continue)
+}
+
+/// evaluates the lists of each recipient of the message on its own
+///
+/// if every recipient has blocked the sender configured on_block_action is
+/// applied to the message (with `tempfail` if any recipient has), otherwise
+/// recipients who have blocked the sender are removed from the message
+/// (unless on_block_action is `continue`, `tag` or `quarantine`, see
+/// [`BlockAction::removes_blocked_recipients`]) and delivery to the others
+/// continues. with `quarantine` the whole message
+/// is held instead, with `tag` the block header is added for them.
+/// message is accepted and a custom header is added only if every remaining
+/// recipient has the sender in allow list.
+/// allow headers already present in the message are always removed.
+fn process_message(
+    api: &(impl ActionContext + SetErrorReply),
+    session: &Session,
+    config: &Config,
+) -> milter::Result<Status> {
+    strip_decision_headers(api, session, config)?;
+
+    match sender_policy(session, config) {
+        AuthenticatedPolicy::Check => {}
+        AuthenticatedPolicy::Bypass => {
+            log::debug!(
+                "Sender is authenticated as `{}`, skip processing message",
+                session.auth_user.as_deref().unwrap_or_default()
+            );
+            return Ok(Status::Continue);
+        }
+        AuthenticatedPolicy::Outbound => {
+            return process_outbound_message(api, session, config);
+        }
+    }
+
+    let sender = match session.sender.as_deref() {
+        Some(sender) => sender,
+        None => {
+            log::warn!("Sender is missing, skip processing message");
+            return Ok(Status::Continue);
+        }
+    };
+
+    if session.recipients.is_empty() {
+        log::warn!("Recipients are missing, skip processing message");
+        return Ok(Status::Continue);
+    }
+
+    if bypasses_null_sender(sender, config) {
+        log::debug!("Message has the null sender, skip processing message");
+        return Ok(Status::Continue);
+    }
+
+    let impersonated = impersonations(session, config);
+    let matches: Vec<(&str, &Match)> =
+        impersonated.iter().map(|(rcpt, matched)| (*rcpt, matched)).collect();
+    // a tagged or quarantined message is not tagged or quarantined again
+    // for blocked recipients
+    let mut tagged = false;
+    let mut quarantined = false;
+    // recipients whose contact is impersonated, removed with `reject`
+    let mut removed: Vec<&str> = Vec::new();
+    if let Some((recipient, matched)) = matches.first() {
+        let action = config.on_impersonation();
         log::warn!(
-            "Either sender or recipient is missing recipient: {:?},sender:{:?}",
+            "From {:?} impersonates `{}` allowed by '{}', applying \
+             on_impersonation '{:?}'",
+            session.header_from_name,
+            matched.entry,
+            recipient,
+            action
+        );
+        match action {
+            // the message is only rejected if it impersonates a contact of
+            // every recipient, like a message blocked by every recipient
+            ImpersonationAction::Reject
+                if matches.len() == session.recipients.len() =>
+            {
+                set_block_reply(api, config, BlockAction::Reject, matched)?;
+                return Ok(Status::Reject);
+            }
+            ImpersonationAction::Reject => {
+                for (recipient, _) in &matches {
+                    log::debug!(
+                        "Removing recipient '{}' whose contact is impersonated",
+                        recipient
+                    );
+                    api.remove_recipient(&format!("<{}>", recipient))?;
+                    removed.push(*recipient);
+                }
+            }
+            ImpersonationAction::Quarantine => {
+                api.quarantine(&format!(
+                    "{} impersonated contact {}",
+                    NAME, matched.entry
+                ))?;
+                quarantined = true;
+            }
+            ImpersonationAction::Tag => {
+                tag_message(api, session, config, "impersonation", &matches)?;
+                tagged = true;
+            }
+            ImpersonationAction::Continue => {}
+        }
+    }
+
+    // allow list can only be checked against authentication results at the
+    // end of the message, RCPT stage ignores `require_authentication`
+    // a message impersonating a contact never gets an allow header
+    let origin = Origin {
+        skip_allow: (config.require_authentication()
+            && !is_authenticated(session, sender))
+            || !impersonated.is_empty(),
+        skip_decoded_allow: config.require_authentication()
+            && !is_decoded_authenticated(session, sender, config),
+        ..origin(session, sender)
+    };
+    let verdicts: Vec<(&str, Verdict)> = session
+        .recipients
+        .iter()
+        .filter(|rcpt| !removed.contains(&rcpt.as_str()))
+        .map(|rcpt| (rcpt.as_str(), recipient_verdict(rcpt, &origin, config)))
+        .collect();
+
+    let action = config.on_block_action();
+    let blocked: Vec<(&str, &Match)> = verdicts
+        .iter()
+        .filter_map(|(recipient, verdict)| match verdict {
+            Verdict::Blocked(matched) => Some((*recipient, matched)),
+            _ => None,
+        })
+        .collect();
+
+    // a message can not be temporarily failed for some recipients only
+    // after DATA, with `tempfail` any blocked recipient fails the message
+    let whole_message = blocked.len() == verdicts.len()
+        || (action == BlockAction::Tempfail && !blocked.is_empty());
+    if whole_message {
+        log::debug!(
+            "Applying config on_block_action '{:?}' to Sender '{}' for '{:?}'",
+            action,
             sender,
-            recipient
+            session.recipients
         );
-        None
+        match action {
+            // reply of the first blocked recipient is used for the whole
+            // message
+            BlockAction::Reject | BlockAction::Tempfail => {
+                set_block_reply(api, config, action, blocked[0].1)?
+            }
+            BlockAction::Quarantine if !quarantined => api.quarantine(
+                &format!("{} blocked sender {}", NAME, sender),
+            )?,
+            BlockAction::Tag if !tagged => {
+                tag_message(api, session, config, "block", &blocked)?
+            }
+            BlockAction::Quarantine
+            | BlockAction::Tag
+            | BlockAction::Discard
+            | BlockAction::Continue => {}
+        }
+        return Ok(action.status());
+    }
+
+    let remove_blocked = action.removes_blocked_recipients();
+    let mut allowed = Vec::new();
+    let mut all_allowed = true;
+    for (recipient, verdict) in &verdicts {
+        match verdict {
+            Verdict::Blocked(_) if remove_blocked => {
+                log::debug!(
+                    "Removing recipient '{}' who blocked sender '{}'",
+                    recipient,
+                    sender
+                );
+                api.remove_recipient(&format!("<{}>", recipient))?;
+            }
+            Verdict::Allowed(matched) => allowed.push((*recipient, matched)),
+            Verdict::Blocked(_) | Verdict::NoMatch => {
+                log::info!("Allow match not found for '{}'", recipient);
+                all_allowed = false;
+            }
+        }
     }
+
+    // blocked recipients are kept, the whole message is held for them or
+    // gets the block header (without Subject prefix, every recipient sees it)
+    if !blocked.is_empty() {
+        match action {
+            BlockAction::Quarantine if !quarantined => {
+                log::debug!(
+                    "Quarantine message of sender '{}' blocked by '{:?}'",
+                    sender,
+                    blocked.iter().map(|(rcpt, _)| rcpt).collect::<Vec<_>>()
+                );
+                api.quarantine(&format!("{} blocked sender {}", NAME, sender))?
+            }
+            BlockAction::Tag if !tagged => {
+                let template = config.block_header();
+                add_headers(api, template, "block", session, &blocked)?
+            }
+            _ => {}
+        }
+    }
+
+    if all_allowed {
+        log::debug!(
+            "Adding Postkeeper Header for sender '{}', recipients '{:?}'",
+            sender,
+            session.recipients
+        );
+        let template = config.allow_header();
+        add_headers(api, template, "allow", session, &allowed)?;
+        // accept the this message
+        return Ok(Status::Accept);
+    }
+
+    Ok(Status::Continue)
+}
+
+/// marks a blocked message with configured block header and Subject prefix
+/// message is delivered, filters down the line decide where it goes
+/// `list` is rendered for the `{list}` placeholder of the header value
+/// Subject is not prefixed twice, a Subject header is added if missing
+fn tag_message(
+    api: &impl ActionContext,
+    session: &Session,
+    config: &Config,
+    list: &str,
+    blocked: &[(&str, &Match)],
+) -> milter::Result<()> {
+    let template = config.block_header();
+    log::debug!("Tagging message with header {}", template.name());
+    add_headers(api, template, list, session, blocked)?;
+
+    let prefix = match config.subject_prefix() {
+        Some(prefix) => prefix,
+        None => return Ok(()),
+    };
+    match session.subject.as_deref().map(str::trim_start) {
+        Some(subject) if subject.starts_with(prefix) => {}
+        Some(subject) => {
+            let subject = format!("{} {}", prefix, subject);
+            api.replace_header("Subject", 1, Some(&subject))?;
+        }
+        None => api.add_header("Subject", prefix)?,
+    }
+    Ok(())
+}
+
+/// adds a decision header for each matched recipient of given list
+/// header value is rendered per recipient, duplicate values are added once
+/// i.e. default `Yes` value is added once for every recipient
+/// `{recipient}` is only rendered if the message has a single recipient
+fn add_headers(
+    api: &impl ActionContext,
+    template: &HeaderTemplate,
+    list: &str,
+    session: &Session,
+    matches: &[(&str, &Match)],
+) -> milter::Result<()> {
+    let sender = session.sender.as_deref().unwrap_or_default();
+    // every recipient gets the same message, `{recipient}` would disclose
+    // the other (and Bcc) recipients, it is left empty unless there is one
+    let shared = session.recipients.len() > 1;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+
+    let mut values: Vec<String> = Vec::new();
+    for (recipient, matched) in matches {
+        let value = template.render(&HeaderFields {
+            list,
+            entry: matched.entry.to_string(),
+            recipient: if shared { "" } else { recipient },
+            sender,
+            timestamp,
+        });
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+
+    for value in &values {
+        api.add_header(template.name(), value)?;
+    }
+    Ok(())
+}
+
+/// removes every allow and block header the message arrived with, filters
+/// down the line only see headers added by postkeeper
+/// header indexes are 1 based and counted per header name, they are removed
+/// last to first
+fn strip_decision_headers(
+    api: &impl ActionContext,
+    session: &Session,
+    config: &Config,
+) -> milter::Result<()> {
+    let headers = [
+        (config.allow_header().name(), session.allow_headers),
+        (config.block_header().name(), session.block_headers),
+    ];
+    for (name, count) in headers {
+        for index in (1..=count).rev() {
+            log::debug!("Removing incoming {} header #{}", name, index);
+            api.replace_header(name, index, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// sets configured SMTP reply for a blocked message or recipient
+/// per entry reply (`;reply=<name>`) takes precedence over the default reply
+/// reply is turned into a 4xx reply if `action` is tempfail
+/// nothing is set if no reply is configured, libmilter default is used then
+fn set_block_reply(
+    api: &impl SetErrorReply,
+    config: &Config,
+    action: BlockAction,
+    matched: &Match,
+) -> milter::Result<()> {
+    if let Some(reply) = config.block_reply(matched.entry.reply()) {
+        let reply = match action {
+            BlockAction::Tempfail => reply.to_tempfail(),
+            _ => reply.clone(),
+        };
+        log::debug!("Setting SMTP reply {:?}", reply);
+        api.set_error_reply(
+            reply.code(),
+            reply.status(),
+            reply.text().into_iter().collect(),
+        )?;
+    }
+    Ok(())
 }
 
 /// consumes and initiates the global config
@@ -281,9 +1009,22 @@ pub fn run(config: Config) {
         .on_abort(abort_callback)
         .on_close(close_callback)
         .on_unknown(unknown_callback)
-        .actions(Actions::REQUEST_MACROS);
+        .actions(
+            Actions::REQUEST_MACROS
+                | Actions::ADD_HEADER
+                | Actions::REPLACE_HEADER
+                | Actions::REMOVE_RECIPIENT
+                | Actions::QUARANTINE,
+        );
 
     log::info!("Starting {}", NAME);
+    log::info!(
+        "Envelope addresses are read from {} and {} macros, MAIL FROM and \
+        RCPT TO arguments are used if the MTA does not send them, a warning \
+        is logged the first time",
+        MACRO_SENDER_ADDR,
+        MACRO_RECPT_ADDR
+    );
 
     // run() is blocking only stops on sigterm or if there is an error
     match milter.run() {
@@ -343,3 +1084,1014 @@ fn print_macro(ctx: &impl MacroValue, name: &str) {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maps::load_test_maps;
+    use pretty_assertions::assert_eq;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    /// records milter actions applied to a message, serves given macros
+    #[derive(Default)]
+    struct MockApi {
+        actions: RefCell<Vec<String>>,
+        macros: HashMap<&'static str, &'static str>,
+    }
+
+    impl MockApi {
+        fn record(&self, action: String) -> milter::Result<()> {
+            self.actions.borrow_mut().push(action);
+            Ok(())
+        }
+
+        fn actions(&self) -> Vec<String> {
+            self.actions.borrow().clone()
+        }
+    }
+
+    impl ActionContext for MockApi {
+        fn replace_sender(&self, from: &str, _: Option<&str>) -> Result<()> {
+            self.record(format!("replace_sender {}", from))
+        }
+
+        fn add_recipient(&self, rcpt: &str, _: Option<&str>) -> Result<()> {
+            self.record(format!("add_recipient {}", rcpt))
+        }
+
+        fn remove_recipient(&self, rcpt: &str) -> Result<()> {
+            self.record(format!("remove_recipient {}", rcpt))
+        }
+
+        fn add_header(&self, name: &str, value: &str) -> Result<()> {
+            self.record(format!("add_header {}: {}", name, value))
+        }
+
+        fn insert_header(
+            &self,
+            i: usize,
+            name: &str,
+            value: &str,
+        ) -> Result<()> {
+            self.record(format!("insert_header {} {}: {}", i, name, value))
+        }
+
+        fn replace_header(
+            &self,
+            name: &str,
+            i: usize,
+            value: Option<&str>,
+        ) -> Result<()> {
+            self.record(format!("replace_header {} {}: {:?}", i, name, value))
+        }
+
+        fn append_body_chunk(&self, _: &[u8]) -> Result<()> {
+            self.record("append_body_chunk".to_owned())
+        }
+
+        fn quarantine(&self, reason: &str) -> Result<()> {
+            self.record(format!("quarantine {}", reason))
+        }
+
+        fn signal_progress(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MacroValue for MockApi {
+        fn macro_value(&self, name: &str) -> Result<Option<&str>> {
+            Ok(self.macros.get(name).copied())
+        }
+    }
+
+    impl SetErrorReply for MockApi {
+        fn set_error_reply(
+            &self,
+            code: &str,
+            ext_code: Option<&str>,
+            msg_lines: Vec<&str>,
+        ) -> Result<()> {
+            self.record(format!(
+                "set_error_reply {} {:?} {:?}",
+                code, ext_code, msg_lines
+            ))
+        }
+    }
+
+    fn rcpt_config() -> Config {
+        Config::from_conf_file("tests/conf.d/reject-at-rcpt.ini").unwrap()
+    }
+
+    fn new_session(sender: &str, recipients: &[&str]) -> Session {
+        Session {
+            sender: Some(sender.to_owned()),
+            recipients: recipients.iter().map(|r| (*r).to_owned()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// config with `on_block_action = discard`
+    fn discard_config() -> Config {
+        Config::from_conf_file("tests/conf.d/valid.ini").unwrap()
+    }
+
+    #[test]
+    fn single_recipient_blocked() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session("kale@example.org", &["reanna@example.com"]);
+
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Discard);
+        assert_eq!(api.actions(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn every_recipient_blocked() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session(
+            "abuser@example.org",
+            &["reanna@example.com", "nobody@example.net"],
+        );
+
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Discard);
+        assert_eq!(api.actions(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn blocked_recipient_removed() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session(
+            "kale@example.org",
+            &["nobody@example.net", "reanna@example.com"],
+        );
+
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec!["remove_recipient <reanna@example.com>".to_owned()]
+        );
+    }
+
+    #[test]
+    fn blocked_recipient_kept_on_continue() {
+        load_test_maps();
+        let api = MockApi::default();
+        let config =
+            Config::from_conf_file("tests/conf.d/on-block-continue.ini")
+                .unwrap();
+        let session = new_session(
+            "kale@example.org",
+            &["nobody@example.net", "reanna@example.com"],
+        );
+
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(api.actions(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn every_recipient_allowed() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session(
+            "status@monitoring.example",
+            &["nobody@example.net", "reanna@example.com"],
+        );
+
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Accept);
+        assert_eq!(
+            api.actions(),
+            vec![format!("add_header {}: Yes", default::ALLOW_HEADER)]
+        );
+    }
+
+    #[test]
+    fn allowed_after_blocked_recipients_removed() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session(
+            "abuser@example.org",
+            &["teresa@example.com", "ceo@corp.example"],
+        );
+
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Accept);
+        assert_eq!(
+            api.actions(),
+            vec![
+                "remove_recipient <teresa@example.com>".to_owned(),
+                format!("add_header {}: Yes", default::ALLOW_HEADER),
+            ]
+        );
+    }
+
+    #[test]
+    fn some_recipients_allowed() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session(
+            "taurean@example.org",
+            &["teresa@example.com", "nobody@example.net"],
+        );
+
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(api.actions(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn blocked_recipient_rejected_at_rcpt() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session("kale@example.org", &[]);
+        let config = rcpt_config();
+
+        let status =
+            process_recipient(&api, &session, "reanna@example.com", &config);
+        assert_eq!(status.unwrap(), Status::Reject);
+
+        // other recipients of the transaction are not affected
+        let status =
+            process_recipient(&api, &session, "nobody@example.net", &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        let status =
+            process_recipient(&api, &session, "teresa@example.com", &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+    }
+
+    #[test]
+    fn allowed_recipient_not_rejected_at_rcpt() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session("pest@example.org", &[]);
+        let config = rcpt_config();
+
+        let status =
+            process_recipient(&api, &session, "ceo@corp.example", &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        let status =
+            process_recipient(&api, &session, "staff@corp.example", &config);
+        assert_eq!(status.unwrap(), Status::Reject);
+    }
+
+    #[test]
+    fn recipient_not_rejected_without_reject_at_rcpt() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session("kale@example.org", &[]);
+
+        // `reject_at_rcpt` is disabled
+        let config =
+            Config::from_conf_file("assets/etc/postkeeper.ini").unwrap();
+        let status =
+            process_recipient(&api, &session, "reanna@example.com", &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+
+        // `on_block_action` is not reject
+        let config = discard_config();
+        let status =
+            process_recipient(&api, &session, "reanna@example.com", &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+    }
+
+    #[test]
+    fn custom_reply_on_reject() {
+        load_test_maps();
+        let api = MockApi::default();
+        let config =
+            Config::from_conf_file("tests/conf.d/replies.ini").unwrap();
+        let session = new_session("kale@example.org", &["reanna@example.com"]);
+
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Reject);
+        assert_eq!(
+            api.actions(),
+            vec![
+                "set_error_reply 550 Some(\"5.7.1\") \
+                 [\"Recipient does not accept mail from you\"]"
+                    .to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_reply_per_entry() {
+        load_test_maps();
+        let api = MockApi::default();
+        let config =
+            Config::from_conf_file("tests/conf.d/replies.ini").unwrap();
+        let session =
+            new_session("fraud@example.org", &["replies@example.com"]);
+
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Reject);
+        assert_eq!(
+            api.actions(),
+            vec![
+                "set_error_reply 554 None [\"Rejected by site policy\"]"
+                    .to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn no_custom_reply_configured() {
+        load_test_maps();
+        let api = MockApi::default();
+        let config =
+            Config::from_conf_file("assets/etc/postkeeper.ini").unwrap();
+        let session =
+            new_session("fraud@example.org", &["replies@example.com"]);
+
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Reject);
+        assert_eq!(api.actions(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn quarantine_blocked_message() {
+        load_test_maps();
+        let config =
+            Config::from_conf_file("tests/conf.d/on-block-quarantine.ini")
+                .unwrap();
+
+        let api = MockApi::default();
+        let session = new_session("kale@example.org", &["reanna@example.com"]);
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec!["quarantine PostKeeper blocked sender kale@example.org"]
+        );
+
+        // blocked recipients are kept, the whole message is held
+        let api = MockApi::default();
+        let session = new_session(
+            "kale@example.org",
+            &["reanna@example.com", "nobody@example.net"],
+        );
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec!["quarantine PostKeeper blocked sender kale@example.org"]
+        );
+    }
+
+    #[test]
+    fn tempfail_blocked_message() {
+        load_test_maps();
+        let config =
+            Config::from_conf_file("tests/conf.d/on-block-tempfail.ini")
+                .unwrap();
+
+        let api = MockApi::default();
+        let session = new_session("kale@example.org", &["reanna@example.com"]);
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Tempfail);
+        assert_eq!(api.actions(), Vec::<String>::new());
+
+        // one blocked recipient fails the whole message, blocked mail is
+        // never delivered and nothing is lost while testing lists
+        let api = MockApi::default();
+        let session = new_session(
+            "kale@example.org",
+            &["nobody@example.net", "reanna@example.com"],
+        );
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Tempfail);
+        assert_eq!(api.actions(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn forged_header_replaced_when_allowed() {
+        load_test_maps();
+        let api = MockApi::default();
+        let mut session = new_session(
+            "status@monitoring.example",
+            &["nobody@example.net", "reanna@example.com"],
+        );
+        session.allow_headers = 2;
+
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Accept);
+        assert_eq!(
+            api.actions(),
+            vec![
+                format!("replace_header 2 {}: None", default::ALLOW_HEADER),
+                format!("replace_header 1 {}: None", default::ALLOW_HEADER),
+                format!("add_header {}: Yes", default::ALLOW_HEADER),
+            ]
+        );
+    }
+
+    #[test]
+    fn forged_header_removed_without_allow_match() {
+        load_test_maps();
+        let api = MockApi::default();
+        let mut session =
+            new_session("stranger@example.org", &["nobody@example.net"]);
+        session.allow_headers = 1;
+
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec![format!("replace_header 1 {}: None", default::ALLOW_HEADER)]
+        );
+    }
+
+    #[test]
+    fn forged_header_removed_when_blocked() {
+        load_test_maps();
+        let api = MockApi::default();
+        let config =
+            Config::from_conf_file("tests/conf.d/on-block-continue.ini")
+                .unwrap();
+        let mut session =
+            new_session("kale@example.org", &["reanna@example.com"]);
+        session.allow_headers = 1;
+
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec![format!("replace_header 1 {}: None", default::ALLOW_HEADER)]
+        );
+    }
+
+    #[test]
+    fn forged_block_header_removed() {
+        load_test_maps();
+        let mut session =
+            new_session("stranger@example.org", &["nobody@example.net"]);
+        session.block_headers = 2;
+
+        let api = MockApi::default();
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec![
+                format!("replace_header 2 {}: None", default::BLOCK_HEADER),
+                format!("replace_header 1 {}: None", default::BLOCK_HEADER),
+            ]
+        );
+
+        // incoming copies are removed before the message is tagged
+        let mut session =
+            new_session("kale@example.org", &["reanna@example.com"]);
+        session.block_headers = 1;
+        let api = MockApi::default();
+        let status = process_message(&api, &session, &tag_config());
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec![
+                "replace_header 1 X-Blocked: None",
+                "add_header X-Blocked: Yes",
+                "add_header Subject: [BLOCKED]",
+            ]
+        );
+    }
+
+    #[test]
+    fn forged_header_removed_without_sender() {
+        let api = MockApi::default();
+        let session = Session {
+            allow_headers: 1,
+            ..Default::default()
+        };
+
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec![format!("replace_header 1 {}: None", default::ALLOW_HEADER)]
+        );
+    }
+
+    /// config with `on_block_action = tag` and `subject_prefix = [BLOCKED]`
+    fn tag_config() -> Config {
+        Config::from_conf_file("tests/conf.d/on-block-tag.ini").unwrap()
+    }
+
+    #[test]
+    fn tag_blocked_message() {
+        load_test_maps();
+        let api = MockApi::default();
+        let mut session =
+            new_session("kale@example.org", &["reanna@example.com"]);
+        session.subject = Some("Hello".to_owned());
+
+        let status = process_message(&api, &session, &tag_config());
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec![
+                "add_header X-Blocked: Yes",
+                "replace_header 1 Subject: Some(\"[BLOCKED] Hello\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn tag_blocked_message_subject() {
+        load_test_maps();
+        let config = tag_config();
+
+        // already prefixed subject is kept
+        let api = MockApi::default();
+        let mut session =
+            new_session("kale@example.org", &["reanna@example.com"]);
+        session.subject = Some("[BLOCKED] Hello".to_owned());
+        process_message(&api, &session, &config).unwrap();
+        assert_eq!(api.actions(), vec!["add_header X-Blocked: Yes"]);
+
+        // missing subject is added
+        let api = MockApi::default();
+        session.subject = None;
+        process_message(&api, &session, &config).unwrap();
+        assert_eq!(
+            api.actions(),
+            vec!["add_header X-Blocked: Yes", "add_header Subject: [BLOCKED]"]
+        );
+    }
+
+    #[test]
+    fn tag_mixed_recipients_header_only() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session(
+            "kale@example.org",
+            &["nobody@example.net", "reanna@example.com"],
+        );
+
+        // Subject is not prefixed, it would be seen by every recipient
+        let status = process_message(&api, &session, &tag_config());
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(api.actions(), vec!["add_header X-Blocked: Yes"]);
+
+        // header value is rendered for the recipient who blocked the sender
+        let api = MockApi::default();
+        let status = process_message(&api, &session, &headers_config());
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec![
+                "add_header X-Postkeeper-Block: list=block; \
+                 entry=kale@example.org; from=kale@example.org"
+            ]
+        );
+    }
+
+    /// config with header names and value templates
+    fn headers_config() -> Config {
+        Config::from_conf_file("tests/conf.d/headers.ini").unwrap()
+    }
+
+    #[test]
+    fn allow_header_template() {
+        load_test_maps();
+        let api = MockApi::default();
+        let mut session = new_session(
+            "status@monitoring.example",
+            &["nobody@example.net", "reanna@example.com"],
+        );
+        session.allow_headers = 1;
+
+        // recipients are not disclosed to each other
+        let status = process_message(&api, &session, &headers_config());
+        assert_eq!(status.unwrap(), Status::Accept);
+        assert_eq!(
+            api.actions(),
+            vec![
+                "replace_header 1 X-PK-Allow: None",
+                "add_header X-PK-Allow: list=allow; \
+                 entry=status@monitoring.example; rcpt=",
+            ]
+        );
+
+        let api = MockApi::default();
+        let session =
+            new_session("status@monitoring.example", &["reanna@example.com"]);
+        let status = process_message(&api, &session, &headers_config());
+        assert_eq!(status.unwrap(), Status::Accept);
+        assert_eq!(
+            api.actions(),
+            vec![
+                "add_header X-PK-Allow: list=allow; \
+                 entry=status@monitoring.example; rcpt=reanna@example.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn block_header_template() {
+        load_test_maps();
+        let api = MockApi::default();
+        let session = new_session(
+            "abuser@example.org",
+            &["reanna@example.com", "nobody@example.net"],
+        );
+
+        let status = process_message(&api, &session, &headers_config());
+        assert_eq!(status.unwrap(), Status::Continue);
+        // same value for both recipients is added once
+        assert_eq!(
+            api.actions(),
+            vec![
+                "add_header X-Postkeeper-Block: list=block; \
+                 entry=abuser@example.org; from=abuser@example.org"
+            ]
+        );
+    }
+
+    #[test]
+    fn client_network_blocked() {
+        load_test_maps();
+        let config = discard_config();
+        let mut session =
+            new_session("anyone@example.org", &["hosts@example.com"]);
+
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+
+        session.client_ip = "192.0.2.10".parse().ok();
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Discard);
+    }
+
+    #[test]
+    fn client_hostname_blocked() {
+        load_test_maps();
+        let config = discard_config();
+        let mut session =
+            new_session("anyone@example.org", &["clients@example.com"]);
+
+        session.helo = Some("mx.partner.example".to_owned());
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Accept);
+
+        session.client_name = Some("mta1.bulkmailer.example".to_owned());
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Discard);
+    }
+
+    #[test]
+    fn header_from_blocked() {
+        load_test_maps();
+        let config = discard_config();
+        let mut session =
+            new_session("bounce-1@esp.example", &["headers@example.com"]);
+
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+
+        session.header_from = header_address("News <info@news.example>");
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Discard);
+    }
+
+    #[test]
+    fn allow_requires_authentication() {
+        load_test_maps();
+        let config =
+            Config::from_conf_file("tests/conf.d/require-authentication.ini")
+                .unwrap();
+        let mut session =
+            new_session("pest@example.org", &["ceo@corp.example"]);
+
+        // mailbox allow entry is ignored, domain key blocks the sender
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Discard);
+
+        session.authentication.add_header(
+            "mx.example.com; spf=pass smtp.mailfrom=pest@example.org",
+            config.authserv_ids(),
+        );
+        let api = MockApi::default();
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Accept);
+        assert_eq!(api.actions(), vec!["add_header X-Postkeeper-Allow: Yes"]);
+
+        // From header is not aligned
+        session.header_from = Some("ceo@partner.example".to_owned());
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Discard);
+
+        // forwarder is authenticated, the decoded SRS sender is not
+        let mut session = new_session(
+            "SRS0=hash=tt=example.org=pest@fwd.example",
+            &["ceo@corp.example"],
+        );
+        session.authentication.add_header(
+            "mx.example.com; spf=pass smtp.mailfrom=fwd.example",
+            config.authserv_ids(),
+        );
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Discard);
+    }
+
+    #[test]
+    fn authenticated_sender_bypass() {
+        load_test_maps();
+        let config =
+            Config::from_conf_file("tests/conf.d/authenticated-bypass.ini")
+                .unwrap();
+        let mut session =
+            new_session("abuser@example.org", &["reanna@example.com"]);
+
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Discard);
+
+        session.auth_user = Some("abuser".to_owned());
+        let api = MockApi::default();
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(api.actions(), Vec::<String>::new());
+
+        // authenticated senders are checked by default
+        let status = process_message(&api, &session, &discard_config());
+        assert_eq!(status.unwrap(), Status::Discard);
+    }
+
+    #[test]
+    fn outbound_recipient_rejected() {
+        load_test_maps();
+        let api = MockApi::default();
+        let config =
+            Config::from_conf_file("tests/conf.d/outbound-reject.ini").unwrap();
+        let mut session = new_session("reanna@example.com", &[]);
+        session.auth_user = Some("reanna".to_owned());
+
+        let status =
+            process_recipient(&api, &session, "kale@example.org", &config);
+        assert_eq!(status.unwrap(), Status::Reject);
+        let status =
+            process_recipient(&api, &session, "nobody@example.net", &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+
+        // lists of recipients are not checked for local senders
+        session.sender = Some("kale@example.org".to_owned());
+        let status =
+            process_recipient(&api, &session, "reanna@example.com", &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        session.recipients = vec!["reanna@example.com".to_owned()];
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(api.actions(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn outbound_recipient_warned() {
+        load_test_maps();
+        let api = MockApi::default();
+        let config =
+            Config::from_conf_file("tests/conf.d/outbound-warn.ini").unwrap();
+        let mut session = new_session(
+            "reanna@example.com",
+            &["kale@example.org", "nobody@example.net"],
+        );
+        session.auth_user = Some("reanna".to_owned());
+
+        let status =
+            process_recipient(&api, &session, "kale@example.org", &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec!["add_header X-Postkeeper-Outbound: outbound kale@example.org"]
+        );
+    }
+
+    #[test]
+    fn null_sender_macro() {
+        let sender = |value: Option<&'static str>, args: &[&str]| {
+            let api = MockApi {
+                macros: value
+                    .map(|v| HashMap::from([(MACRO_SENDER_ADDR, v)]))
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+            get_sender(&api, args)
+        };
+
+        assert_eq!(
+            sender(Some("bob@example.org"), &[]),
+            Some("bob@example.org".to_owned())
+        );
+        assert_eq!(sender(Some(""), &[]), Some(String::new()));
+        assert_eq!(sender(Some("<>"), &[]), Some(String::new()));
+        assert_eq!(sender(None, &[]), None);
+
+        // `MAIL FROM` arguments are used without macro
+        assert_eq!(
+            sender(None, &["<Bob@example.org>", "SIZE=1024"]),
+            Some("Bob@example.org".to_owned())
+        );
+        assert_eq!(sender(None, &["<>", "RET=HDRS"]), Some(String::new()));
+        assert_eq!(
+            sender(Some("bob@example.org"), &["<eve@example.org>"]),
+            Some("bob@example.org".to_owned())
+        );
+    }
+
+    #[test]
+    fn null_sender_policy() {
+        load_test_maps();
+        let session = new_session("", &["reanna@example.com"]);
+        let check = discard_config();
+        let block =
+            Config::from_conf_file("tests/conf.d/null-sender-block.ini")
+                .unwrap();
+        let bypass =
+            Config::from_conf_file("tests/conf.d/null-sender-bypass.ini")
+                .unwrap();
+
+        // `null_sender = check` only applies `<>` entries
+        let status = process_message(&MockApi::default(), &session, &check);
+        assert_eq!(status.unwrap(), Status::Continue);
+        let status = process_message(&MockApi::default(), &session, &block);
+        assert_eq!(status.unwrap(), Status::Discard);
+
+        // an allow entry overrides `null_sender = block`
+        let session = new_session("", &["bounces@example.net"]);
+        let status = process_message(&MockApi::default(), &session, &block);
+        assert_eq!(status.unwrap(), Status::Accept);
+
+        let session = new_session("", &["bounces@example.com"]);
+        let status = process_message(&MockApi::default(), &session, &check);
+        assert_eq!(status.unwrap(), Status::Discard);
+        let status = process_message(&MockApi::default(), &session, &bypass);
+        assert_eq!(status.unwrap(), Status::Continue);
+    }
+
+    #[test]
+    fn recipient_from_smtp_args() {
+        let api = MockApi::default();
+        let args = ["<alice@example.com>", "NOTIFY=NEVER"];
+        assert_eq!(
+            envelope_address(&api, MACRO_RECPT_ADDR, &args),
+            Some("alice@example.com".to_owned())
+        );
+        // fallback is reported once per process
+        assert!(SMTP_ARGS_USED.load(Ordering::Relaxed));
+
+        let api = MockApi {
+            macros: HashMap::from([(MACRO_RECPT_ADDR, "bob@example.com")]),
+            ..Default::default()
+        };
+        assert_eq!(
+            envelope_address(&api, MACRO_RECPT_ADDR, &args),
+            Some("bob@example.com".to_owned())
+        );
+    }
+
+    #[test]
+    fn mailing_list_blocked() {
+        load_test_maps();
+        let config = discard_config();
+        let mut session =
+            new_session("anyone@example.org", &["lists@example.com"]);
+
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+
+        session.list_id = list_id("Noisy <noisy.lists.example.org>");
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Discard);
+
+        session.list_id = list_id("Developers <dev.lists.example.org>");
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Accept);
+
+        session.list_post =
+            list_post_address("<mailto:chatter@lists.example.org>");
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Discard);
+    }
+
+    #[test]
+    fn impersonation_rejected() {
+        load_test_maps();
+        let config =
+            Config::from_conf_file("tests/conf.d/on-impersonation-reject.ini")
+                .unwrap();
+        let from = "\"Jane CFO\" <jane@evil.example>";
+        let mut session =
+            new_session("jane@evil.example", &["impersonated@example.com"]);
+        session.header_from = header_address(from);
+        session.header_from_name = header_display_name(from);
+
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Reject);
+
+        // impersonation is only logged by default
+        let config = discard_config();
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+
+        let config =
+            Config::from_conf_file("tests/conf.d/on-impersonation-reject.ini")
+                .unwrap();
+        let from = "\"Jane CFO\" <jane.cfo@partner.example>";
+        session.header_from = header_address(from);
+        session.header_from_name = header_display_name(from);
+        let status = process_message(&MockApi::default(), &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+
+        // only the recipient whose contact is impersonated is removed
+        let from = "\"Jane CFO\" <jane@evil.example>";
+        let mut session = new_session(
+            "jane@evil.example",
+            &["impersonated@example.com", "nobody@example.net"],
+        );
+        session.header_from = header_address(from);
+        session.header_from_name = header_display_name(from);
+        let api = MockApi::default();
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec!["remove_recipient <impersonated@example.com>"]
+        );
+    }
+
+    #[test]
+    fn impersonation_quarantined() {
+        load_test_maps();
+        let config = Config::from_conf_file(
+            "tests/conf.d/on-impersonation-quarantine.ini",
+        )
+        .unwrap();
+        // sender is blocked as well, the message is quarantined once
+        let from = "Jane CFO <abuser@example.org>";
+        let mut session =
+            new_session("abuser@example.org", &["impersonated@example.com"]);
+        session.header_from = header_address(from);
+        session.header_from_name = header_display_name(from);
+        let api = MockApi::default();
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec!["quarantine PostKeeper impersonated contact \
+                  jane.cfo@partner.example"]
+        );
+    }
+
+    #[test]
+    fn impersonation_tagged() {
+        load_test_maps();
+        let config =
+            Config::from_conf_file("tests/conf.d/on-impersonation-tag.ini")
+                .unwrap();
+        let from = "Jane CFO <jane.cfo@partner-example.com>";
+        let mut session =
+            new_session("jane@partner.example", &["impersonated@example.com"]);
+        session.header_from = header_address(from);
+        session.header_from_name = header_display_name(from);
+        session.subject = Some("Wire transfer".to_owned());
+
+        // `@partner.example` allow entry is ignored
+        let api = MockApi::default();
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec![
+                "add_header X-Postkeeper-Block: \
+                 impersonation jane.cfo@partner.example",
+                "replace_header 1 Subject: Some(\"[SPOOF] Wire transfer\")",
+            ]
+        );
+
+        // sender is blocked as well, the message is tagged once
+        let from = "Jane CFO <abuser@example.org>";
+        let mut session =
+            new_session("abuser@example.org", &["impersonated@example.com"]);
+        session.header_from = header_address(from);
+        session.header_from_name = header_display_name(from);
+        let api = MockApi::default();
+        let status = process_message(&api, &session, &config);
+        assert_eq!(status.unwrap(), Status::Continue);
+        assert_eq!(
+            api.actions(),
+            vec![
+                "add_header X-Postkeeper-Block: \
+                 impersonation jane.cfo@partner.example",
+                "add_header Subject: [SPOOF]",
+            ]
+        );
+    }
+}
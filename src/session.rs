@@ -0,0 +1,58 @@
+//! Postkeeper per connection milter state
+
+use crate::auth::Authentication;
+use std::net::IpAddr;
+
+/// Holds the state of a single SMTP connection between milter callbacks
+/// it lives in the milter context data from `connect` until `close`
+/// message scoped values are cleared for each message of the connection
+#[derive(Debug, Default)]
+pub struct Session {
+    /// IP address of the connected client, if the MTA sent it
+    pub client_ip: Option<IpAddr>,
+    /// verified hostname of the connected client, if known
+    pub client_name: Option<String>,
+    /// name the client sent with the last `HELO`/`EHLO`
+    pub helo: Option<String>,
+    /// envelope sender of the current message
+    pub sender: Option<String>,
+    /// SASL login name of the client (`{auth_authen}`) for the current
+    /// message, None if the client is not authenticated
+    pub auth_user: Option<String>,
+    /// address of the first `From:` header of the current message
+    pub header_from: Option<String>,
+    /// display name of the first `From:` header of the current message
+    pub header_from_name: Option<String>,
+    /// identifier of the first `List-Id` header of the current message
+    pub list_id: Option<String>,
+    /// posting address of the first `List-Post` header of the current message
+    pub list_post: Option<String>,
+    /// domains of passing SPF and DKIM results of the current message
+    pub authentication: Authentication,
+    /// envelope recipients of the current message in `RCPT TO` order
+    pub recipients: Vec<String>,
+    /// number of allow headers (`allow_header`) found in the incoming message
+    pub allow_headers: usize,
+    /// number of block headers (`block_header`) found in the incoming message
+    pub block_headers: usize,
+    /// value of the first Subject header of the current message
+    pub subject: Option<String>,
+}
+
+impl Session {
+    /// clears all message scoped values
+    /// called at the start and at the end of each message
+    pub fn reset_message(&mut self) {
+        self.sender = None;
+        self.auth_user = None;
+        self.header_from = None;
+        self.header_from_name = None;
+        self.list_id = None;
+        self.list_post = None;
+        self.authentication = Authentication::default();
+        self.recipients.clear();
+        self.allow_headers = 0;
+        self.block_headers = 0;
+        self.subject = None;
+    }
+}